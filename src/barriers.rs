@@ -0,0 +1,373 @@
+use crate::device::*;
+
+use ash::vk;
+use std::collections::HashMap;
+
+
+// Keeps track of each image's layout and of the last stages and accesses to touch it, so
+// recording code only has to say what it's about to use an image for. use_image() works out
+// the barrier that needs, and flush() records everything queued so far in one
+// vkCmdPipelineBarrier2, or in one legacy vkCmdPipelineBarrier when synchronization2 isn't
+// enabled. A tracker assumes the command buffers it records into execute in recording order on
+// one queue, so keep one per queue. Queue family ownership transfers aren't handled; share
+// images between families with CONCURRENT as upload.rs does.
+
+// What an image is about to be used for. The stages and accesses are ones that exist in the
+// legacy flags too, so the fallback can use them as they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageUse {
+    TransferSrc,
+    TransferDst,
+    // Sampled or read as an input from fragment or compute shaders.
+    ShaderRead,
+    // Read and written as a storage image by compute shaders.
+    Storage,
+    ColorAttachment,
+    DepthAttachment,
+    Present,
+    // Just this layout, with nothing after it in the command buffer using the image. For
+    // handing an image over to code that doesn't track it, such as at the end of an upload.
+    Layout(vk::ImageLayout),
+}
+
+impl ImageUse {
+    fn layout(self) -> vk::ImageLayout {
+        match self {
+            ImageUse::TransferSrc => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ImageUse::TransferDst => vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            ImageUse::ShaderRead => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ImageUse::Storage => vk::ImageLayout::GENERAL,
+            ImageUse::ColorAttachment => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ImageUse::DepthAttachment => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            ImageUse::Present => vk::ImageLayout::PRESENT_SRC_KHR,
+            ImageUse::Layout(layout) => layout,
+        }
+    }
+
+    fn stage(self) -> vk::PipelineStageFlags2 {
+        match self {
+            ImageUse::TransferSrc | ImageUse::TransferDst => vk::PipelineStageFlags2::TRANSFER,
+            ImageUse::ShaderRead => vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER,
+            ImageUse::Storage => vk::PipelineStageFlags2::COMPUTE_SHADER,
+            ImageUse::ColorAttachment => vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            ImageUse::DepthAttachment => {
+                vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS
+            }
+            ImageUse::Present | ImageUse::Layout(_) => vk::PipelineStageFlags2::NONE,
+        }
+    }
+
+    fn access(self) -> vk::AccessFlags2 {
+        match self {
+            ImageUse::TransferSrc => vk::AccessFlags2::TRANSFER_READ,
+            ImageUse::TransferDst => vk::AccessFlags2::TRANSFER_WRITE,
+            ImageUse::ShaderRead => vk::AccessFlags2::SHADER_READ,
+            ImageUse::Storage => vk::AccessFlags2::SHADER_READ | vk::AccessFlags2::SHADER_WRITE,
+            ImageUse::ColorAttachment => {
+                vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE
+            }
+            ImageUse::DepthAttachment => {
+                vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE
+            }
+            ImageUse::Present | ImageUse::Layout(_) => vk::AccessFlags2::NONE,
+        }
+    }
+}
+
+fn writes(access: vk::AccessFlags2) -> vk::AccessFlags2 {
+    access
+        & (vk::AccessFlags2::SHADER_WRITE
+            | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE
+            | vk::AccessFlags2::TRANSFER_WRITE
+            | vk::AccessFlags2::HOST_WRITE
+            | vk::AccessFlags2::MEMORY_WRITE)
+}
+
+struct ImageState {
+    aspect: vk::ImageAspectFlags,
+    layout: vk::ImageLayout,
+    // Everything since the last barrier: one use that writes, or any number that only read.
+    stage: vk::PipelineStageFlags2,
+    access: vk::AccessFlags2,
+}
+
+struct PendingBarrier {
+    image: vk::Image,
+    aspect: vk::ImageAspectFlags,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_stage: vk::PipelineStageFlags2,
+    src_access: vk::AccessFlags2,
+    dst_stage: vk::PipelineStageFlags2,
+    dst_access: vk::AccessFlags2,
+}
+
+impl PendingBarrier {
+    fn range(&self) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask: self.aspect,
+            base_mip_level: 0,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        }
+    }
+}
+
+pub struct LayoutTracker {
+    images: HashMap<vk::Image, ImageState>,
+    pending: Vec<PendingBarrier>,
+}
+
+impl LayoutTracker {
+    pub fn new() -> Self {
+        LayoutTracker {
+            images: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Starts tracking a whole image that's currently in layout, with nothing still using it.
+    // Use UNDEFINED for a new image.
+    pub fn track(&mut self, image: vk::Image, aspect: vk::ImageAspectFlags, layout: vk::ImageLayout) {
+        self.images.insert(
+            image,
+            ImageState {
+                aspect,
+                layout,
+                stage: vk::PipelineStageFlags2::NONE,
+                access: vk::AccessFlags2::NONE,
+            },
+        );
+    }
+
+    pub fn forget(&mut self, image: vk::Image) {
+        self.images.remove(&image);
+        self.pending.retain(|b| b.image != image);
+    }
+
+    pub fn layout(&self, image: vk::Image) -> Option<vk::ImageLayout> {
+        self.images.get(&image).map(|state| state.layout)
+    }
+
+    // The next use transitions from UNDEFINED, which throws away the contents but is cheaper.
+    pub fn discard(&mut self, image: vk::Image) {
+        if let Some(state) = self.images.get_mut(&image) {
+            state.layout = vk::ImageLayout::UNDEFINED;
+        }
+    }
+
+    // Queues whatever barrier is needed before image is used as usage. Reads that follow other
+    // reads in the same layout need none. Record them with flush() before the commands that
+    // use the image.
+    pub fn use_image(&mut self, image: vk::Image, usage: ImageUse) {
+        let state = self.images.get_mut(&image).expect("Using an image the layout tracker doesn't know");
+        let (layout, stage, access) = (usage.layout(), usage.stage(), usage.access());
+
+        let hazard = layout != state.layout || !writes(state.access).is_empty() || !writes(access).is_empty();
+        if !hazard {
+            state.stage |= stage;
+            state.access |= access;
+            // A barrier still waiting for flush() has to cover this use as well.
+            if let Some(barrier) = self.pending.iter_mut().find(|b| b.image == image) {
+                barrier.dst_stage |= stage;
+                barrier.dst_access |= access;
+            }
+            return;
+        }
+
+        // Earlier reads only need to have finished, but earlier writes have to be made available.
+        let src_access = writes(state.access);
+        match self.pending.iter_mut().find(|b| b.image == image) {
+            // Nothing can have used the image since, so the barrier can go straight to the new use.
+            Some(barrier) => {
+                barrier.new_layout = layout;
+                barrier.dst_stage = stage;
+                barrier.dst_access = access;
+            }
+            None => self.pending.push(PendingBarrier {
+                image,
+                aspect: state.aspect,
+                old_layout: state.layout,
+                new_layout: layout,
+                src_stage: state.stage,
+                src_access,
+                dst_stage: stage,
+                dst_access: access,
+            }),
+        }
+        state.layout = layout;
+        state.stage = stage;
+        state.access = access;
+    }
+
+    // Records the queued barriers, if there are any.
+    pub unsafe fn flush(&mut self, device: &Device, command_buffer: vk::CommandBuffer) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        if device.has_feature("synchronization2") {
+            let barriers: Vec<vk::ImageMemoryBarrier2> = self
+                .pending
+                .iter()
+                .map(|b| {
+                    vk::ImageMemoryBarrier2::default()
+                        .src_stage_mask(b.src_stage)
+                        .src_access_mask(b.src_access)
+                        .dst_stage_mask(b.dst_stage)
+                        .dst_access_mask(b.dst_access)
+                        .old_layout(b.old_layout)
+                        .new_layout(b.new_layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(b.image)
+                        .subresource_range(b.range())
+                })
+                .collect();
+            device
+                .device
+                .cmd_pipeline_barrier2(command_buffer, &vk::DependencyInfo::default().image_memory_barriers(&barriers));
+        } else {
+            // One call has one pair of stage masks, so they cover every barrier in it. The
+            // legacy flags have no NONE stage, so the pipeline's ends stand in for it.
+            let mut src_stage = vk::PipelineStageFlags::empty();
+            let mut dst_stage = vk::PipelineStageFlags::empty();
+            let barriers: Vec<vk::ImageMemoryBarrier> = self
+                .pending
+                .iter()
+                .map(|b| {
+                    src_stage |= vk::PipelineStageFlags::from_raw(b.src_stage.as_raw() as u32);
+                    dst_stage |= vk::PipelineStageFlags::from_raw(b.dst_stage.as_raw() as u32);
+                    vk::ImageMemoryBarrier::default()
+                        .src_access_mask(vk::AccessFlags::from_raw(b.src_access.as_raw() as u32))
+                        .dst_access_mask(vk::AccessFlags::from_raw(b.dst_access.as_raw() as u32))
+                        .old_layout(b.old_layout)
+                        .new_layout(b.new_layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(b.image)
+                        .subresource_range(b.range())
+                })
+                .collect();
+            if src_stage.is_empty() {
+                src_stage = vk::PipelineStageFlags::TOP_OF_PIPE;
+            }
+            if dst_stage.is_empty() {
+                dst_stage = vk::PipelineStageFlags::BOTTOM_OF_PIPE;
+            }
+            device.device.cmd_pipeline_barrier(
+                command_buffer,
+                src_stage,
+                dst_stage,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    fn image() -> vk::Image {
+        vk::Image::from_raw(1)
+    }
+
+    fn tracker(layout: vk::ImageLayout) -> LayoutTracker {
+        let mut tracker = LayoutTracker::new();
+        tracker.track(image(), vk::ImageAspectFlags::COLOR, layout);
+        tracker
+    }
+
+    #[test]
+    fn read_after_read_needs_no_barrier() {
+        let mut t = tracker(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        t.use_image(image(), ImageUse::ShaderRead);
+        t.use_image(image(), ImageUse::ShaderRead);
+        assert!(t.pending.is_empty());
+    }
+
+    #[test]
+    fn transition_from_undefined() {
+        let mut t = tracker(vk::ImageLayout::UNDEFINED);
+        t.use_image(image(), ImageUse::TransferDst);
+        let b = &t.pending[0];
+        assert_eq!(t.pending.len(), 1);
+        assert_eq!(b.old_layout, vk::ImageLayout::UNDEFINED);
+        assert_eq!(b.new_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        assert_eq!(b.src_stage, vk::PipelineStageFlags2::NONE);
+        assert_eq!(b.src_access, vk::AccessFlags2::NONE);
+        assert_eq!(b.dst_stage, vk::PipelineStageFlags2::TRANSFER);
+        assert_eq!(b.dst_access, vk::AccessFlags2::TRANSFER_WRITE);
+        assert_eq!(t.layout(image()), Some(vk::ImageLayout::TRANSFER_DST_OPTIMAL));
+    }
+
+    #[test]
+    fn write_after_read_only_waits_for_the_reads() {
+        let mut t = tracker(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        t.use_image(image(), ImageUse::ShaderRead);
+        assert!(t.pending.is_empty());
+        t.use_image(image(), ImageUse::TransferDst);
+        let b = &t.pending[0];
+        assert_eq!(b.old_layout, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        assert_eq!(b.new_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        assert_eq!(b.src_stage, vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER);
+        assert!(b.src_access.is_empty());
+        assert_eq!(b.dst_stage, vk::PipelineStageFlags2::TRANSFER);
+        assert_eq!(b.dst_access, vk::AccessFlags2::TRANSFER_WRITE);
+    }
+
+    #[test]
+    fn read_after_write_makes_the_write_available() {
+        let mut t = tracker(vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        t.use_image(image(), ImageUse::TransferDst);
+        // What flush() does once it's recorded them.
+        t.pending.clear();
+        t.use_image(image(), ImageUse::ShaderRead);
+        let b = &t.pending[0];
+        assert_eq!(b.src_stage, vk::PipelineStageFlags2::TRANSFER);
+        assert_eq!(b.src_access, vk::AccessFlags2::TRANSFER_WRITE);
+        assert_eq!(b.old_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        assert_eq!(b.new_layout, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    }
+
+    #[test]
+    fn reads_merge_into_a_pending_barrier() {
+        let mut t = tracker(vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+        t.use_image(image(), ImageUse::TransferSrc);
+        t.use_image(image(), ImageUse::TransferSrc);
+        assert_eq!(t.pending.len(), 1);
+        assert_eq!(t.pending[0].new_layout, vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+        assert_eq!(t.pending[0].dst_stage, vk::PipelineStageFlags2::TRANSFER);
+        assert_eq!(t.pending[0].dst_access, vk::AccessFlags2::TRANSFER_READ);
+    }
+
+    #[test]
+    fn hazard_retargets_a_pending_barrier() {
+        let mut t = tracker(vk::ImageLayout::UNDEFINED);
+        t.use_image(image(), ImageUse::TransferDst);
+        t.use_image(image(), ImageUse::ColorAttachment);
+        assert_eq!(t.pending.len(), 1);
+        let b = &t.pending[0];
+        assert_eq!(b.old_layout, vk::ImageLayout::UNDEFINED);
+        assert_eq!(b.new_layout, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        assert_eq!(b.src_stage, vk::PipelineStageFlags2::NONE);
+        assert_eq!(b.dst_stage, vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT);
+    }
+
+    #[test]
+    fn forget_drops_pending_barriers() {
+        let mut t = tracker(vk::ImageLayout::UNDEFINED);
+        t.use_image(image(), ImageUse::TransferDst);
+        t.forget(image());
+        assert!(t.pending.is_empty());
+        assert_eq!(t.layout(image()), None);
+    }
+}
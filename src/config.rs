@@ -1,3 +1,411 @@
-pub const VK_DEBUG_LAYER: bool = true;
-pub const VK_DYNAMIC_VIEW_SIZE: bool = true;
+use crate::pipelinecache::*;
 
+use ash::vk;
+use std::path::PathBuf;
+
+
+// Everything here can be set from code with the builder methods, and then overridden at run time
+// with the VK_TRIANGLE_* environment variables by calling apply_env().
+
+// A layer, extension or device feature we'd like. Missing optional ones are skipped with a
+// warning, missing required ones fail creation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Requested {
+    pub name: String,
+    pub required: bool,
+}
+
+impl Requested {
+    pub fn required(name: &str) -> Self {
+        Requested { name: name.to_string(), required: true }
+    }
+
+    pub fn optional(name: &str) -> Self {
+        Requested { name: name.to_string(), required: false }
+    }
+
+    // "NAME" is required, "NAME?" is optional.
+    pub fn parse(s: &str) -> Self {
+        match s.strip_suffix('?') {
+            Some(name) => Requested::optional(name),
+            None => Requested::required(s),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InstanceConfig {
+    pub app_name: String,
+    pub engine_name: String,
+    pub api_version: u32,
+    pub extra_layers: Vec<Requested>,
+    pub extra_extensions: Vec<Requested>,
+    pub validation: bool,
+    pub validation_features: ValidationFeatures,
+    // Route driver host allocations through hostmem::HostMemoryTracker.
+    pub track_host_memory: bool,
+}
+
+// Optional checks in the Khronos validation layer, turned on through VK_EXT_validation_features.
+// They only take effect when validation itself is on.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationFeatures {
+    pub best_practices: bool,
+    pub synchronization: bool,
+    pub gpu_assisted: bool,
+    pub debug_printf: bool,
+}
+
+impl ValidationFeatures {
+    pub fn any(&self) -> bool {
+        self.best_practices || self.synchronization || self.gpu_assisted || self.debug_printf
+    }
+}
+
+impl Default for InstanceConfig {
+    fn default() -> Self {
+        InstanceConfig {
+            app_name: "Triangle 1".to_string(),
+            engine_name: "Triangle 1".to_string(),
+            api_version: vk::make_api_version(0, 1, 3, 0),
+            extra_layers: Vec::new(),
+            extra_extensions: Vec::new(),
+            validation: true,
+            validation_features: ValidationFeatures::default(),
+            track_host_memory: false,
+        }
+    }
+}
+
+impl InstanceConfig {
+    pub fn app_name(mut self, name: &str) -> Self {
+        self.app_name = name.to_string();
+        self
+    }
+
+    pub fn engine_name(mut self, name: &str) -> Self {
+        self.engine_name = name.to_string();
+        self
+    }
+
+    pub fn api_version(mut self, major: u32, minor: u32) -> Self {
+        self.api_version = vk::make_api_version(0, major, minor, 0);
+        self
+    }
+
+    pub fn layer(mut self, name: &str) -> Self {
+        self.extra_layers.push(Requested::required(name));
+        self
+    }
+
+    pub fn optional_layer(mut self, name: &str) -> Self {
+        self.extra_layers.push(Requested::optional(name));
+        self
+    }
+
+    pub fn extension(mut self, name: &str) -> Self {
+        self.extra_extensions.push(Requested::required(name));
+        self
+    }
+
+    pub fn optional_extension(mut self, name: &str) -> Self {
+        self.extra_extensions.push(Requested::optional(name));
+        self
+    }
+
+    pub fn validation(mut self, enable: bool) -> Self {
+        self.validation = enable;
+        self
+    }
+
+    pub fn best_practices(mut self, enable: bool) -> Self {
+        self.validation_features.best_practices = enable;
+        self
+    }
+
+    pub fn sync_validation(mut self, enable: bool) -> Self {
+        self.validation_features.synchronization = enable;
+        self
+    }
+
+    pub fn gpu_assisted_validation(mut self, enable: bool) -> Self {
+        self.validation_features.gpu_assisted = enable;
+        self
+    }
+
+    pub fn debug_printf(mut self, enable: bool) -> Self {
+        self.validation_features.debug_printf = enable;
+        self
+    }
+
+    pub fn track_host_memory(mut self, enable: bool) -> Self {
+        self.track_host_memory = enable;
+        self
+    }
+
+    pub fn apply_env(mut self) -> Self {
+        if let Some(name) = env_string("VK_TRIANGLE_APP_NAME") {
+            self.app_name = name;
+        }
+        if let Some(name) = env_string("VK_TRIANGLE_ENGINE_NAME") {
+            self.engine_name = name;
+        }
+        if let Some(version) = env_parse("VK_TRIANGLE_API_VERSION", parse_api_version) {
+            self.api_version = version;
+        }
+        if let Some(layers) = env_list("VK_TRIANGLE_LAYERS") {
+            self.extra_layers.extend(layers.iter().map(|s| Requested::parse(s)));
+        }
+        if let Some(extensions) = env_list("VK_TRIANGLE_INSTANCE_EXTENSIONS") {
+            self.extra_extensions.extend(extensions.iter().map(|s| Requested::parse(s)));
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_VALIDATION", parse_bool) {
+            self.validation = enable;
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_BEST_PRACTICES", parse_bool) {
+            self.validation_features.best_practices = enable;
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_SYNC_VALIDATION", parse_bool) {
+            self.validation_features.synchronization = enable;
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_GPU_ASSISTED", parse_bool) {
+            self.validation_features.gpu_assisted = enable;
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_DEBUG_PRINTF", parse_bool) {
+            self.validation_features.debug_printf = enable;
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_TRACK_HOST_MEMORY", parse_bool) {
+            self.track_host_memory = enable;
+        }
+        self
+    }
+}
+
+// Forces a particular physical device instead of the best scoring one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceSelector {
+    // Position in vkEnumeratePhysicalDevices order, as printed by --vkinfo.
+    Index(usize),
+    // Case-insensitive substring of the device name.
+    Name(String),
+    // VkPhysicalDeviceIDProperties::deviceUUID, which stays the same across runs and reboots.
+    Uuid([u8; 16]),
+}
+
+impl DeviceSelector {
+    // A plain number is an index, 32 hex digits (dashes allowed) a UUID, anything else a name.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return None;
+        }
+        if let Ok(index) = s.parse::<usize>() {
+            return Some(DeviceSelector::Index(index));
+        }
+        if let Some(uuid) = parse_uuid(s) {
+            return Some(DeviceSelector::Uuid(uuid));
+        }
+        Some(DeviceSelector::Name(s.to_string()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    pub extensions: Vec<Requested>,
+    // Field names from the ash feature structs, such as "fill_mode_non_solid" or
+    // "timeline_semaphore". Devices missing a required one aren't considered at all.
+    pub features: Vec<Requested>,
+    pub dynamic_viewport: bool,
+    pub device: Option<DeviceSelector>,
+    // Records the draws into secondary command buffers on this many threads. 0 records them
+    // inline in the frame's primary command buffer.
+    pub recording_threads: usize,
+    // Where the pipeline cache is loaded from and saved back to. None keeps it in memory only.
+    pub pipeline_cache: Option<PathBuf>,
+    // Tracks submissions with a timeline semaphore per queue when the device has them.
+    // Otherwise, or when this is off, every submit signals a fence instead.
+    pub timeline_semaphores: bool,
+    // Records barriers with vkCmdPipelineBarrier2 when the device has synchronization2.
+    pub synchronization2: bool,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            extensions: Vec::new(),
+            features: Vec::new(),
+            dynamic_viewport: true,
+            device: None,
+            recording_threads: 0,
+            pipeline_cache: Some(default_pipeline_cache_path()),
+            timeline_semaphores: true,
+            synchronization2: true,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn device(mut self, selector: DeviceSelector) -> Self {
+        self.device = Some(selector);
+        self
+    }
+
+    pub fn extension(mut self, name: &str) -> Self {
+        self.extensions.push(Requested::required(name));
+        self
+    }
+
+    pub fn optional_extension(mut self, name: &str) -> Self {
+        self.extensions.push(Requested::optional(name));
+        self
+    }
+
+    pub fn feature(mut self, name: &str) -> Self {
+        self.features.push(Requested::required(name));
+        self
+    }
+
+    pub fn optional_feature(mut self, name: &str) -> Self {
+        self.features.push(Requested::optional(name));
+        self
+    }
+
+    pub fn dynamic_viewport(mut self, enable: bool) -> Self {
+        self.dynamic_viewport = enable;
+        self
+    }
+
+    pub fn recording_threads(mut self, threads: usize) -> Self {
+        self.recording_threads = threads;
+        self
+    }
+
+    pub fn pipeline_cache(mut self, path: Option<PathBuf>) -> Self {
+        self.pipeline_cache = path;
+        self
+    }
+
+    pub fn timeline_semaphores(mut self, enable: bool) -> Self {
+        self.timeline_semaphores = enable;
+        self
+    }
+
+    pub fn synchronization2(mut self, enable: bool) -> Self {
+        self.synchronization2 = enable;
+        self
+    }
+
+    pub fn apply_env(mut self) -> Self {
+        if let Some(extensions) = env_list("VK_TRIANGLE_DEVICE_EXTENSIONS") {
+            self.extensions.extend(extensions.iter().map(|s| Requested::parse(s)));
+        }
+        if let Some(features) = env_list("VK_TRIANGLE_DEVICE_FEATURES") {
+            self.features.extend(features.iter().map(|s| Requested::parse(s)));
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_DYNAMIC_VIEWPORT", parse_bool) {
+            self.dynamic_viewport = enable;
+        }
+        if let Some(selector) = env_parse("VK_TRIANGLE_DEVICE", DeviceSelector::parse) {
+            self.device = Some(selector);
+        }
+        if let Some(threads) = env_parse("VK_TRIANGLE_RECORDING_THREADS", |s| s.parse::<usize>().ok()) {
+            self.recording_threads = threads;
+        }
+        if let Some(path) = env_string("VK_TRIANGLE_PIPELINE_CACHE") {
+            self.pipeline_cache = match parse_bool(path.trim()) {
+                Some(false) => None,
+                Some(true) => Some(default_pipeline_cache_path()),
+                None => Some(PathBuf::from(path.trim())),
+            };
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_TIMELINE_SEMAPHORES", parse_bool) {
+            self.timeline_semaphores = enable;
+        }
+        if let Some(enable) = env_parse("VK_TRIANGLE_SYNCHRONIZATION2", parse_bool) {
+            self.synchronization2 = enable;
+        }
+        self
+    }
+}
+
+// VK_TRIANGLE_INJECT_FAULT makes a window's render fail as if the driver had returned an
+// error, to exercise the recovery paths: "device_lost" or "surface_lost", optionally followed
+// by "@N" for the frame to fail on.
+pub fn fault_injection_from_env() -> Option<(usize, vk::Result)> {
+    env_parse("VK_TRIANGLE_INJECT_FAULT", parse_fault)
+}
+
+pub fn parse_fault(s: &str) -> Option<(usize, vk::Result)> {
+    let (name, frame) = match s.split_once('@') {
+        Some((name, frame)) => (name, frame.trim().parse::<usize>().ok()?),
+        None => (s, 100),
+    };
+    let fault = match name.trim().to_ascii_lowercase().as_str() {
+        "device_lost" => vk::Result::ERROR_DEVICE_LOST,
+        "surface_lost" => vk::Result::ERROR_SURFACE_LOST_KHR,
+        _ => return None,
+    };
+    Some((frame, fault))
+}
+
+
+fn env_string(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|s| !s.trim().is_empty())
+}
+
+fn env_list(var: &str) -> Option<Vec<String>> {
+    env_string(var).map(|s| {
+        s.split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
+
+fn env_parse<T>(var: &str, parse: fn(&str) -> Option<T>) -> Option<T> {
+    let value = env_string(var)?;
+    let parsed = parse(value.trim());
+    if parsed.is_none() {
+        log::warn!("Ignoring {}={:?}, could not parse it", var, value);
+    }
+    parsed
+}
+
+pub fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_ascii_lowercase().as_str() {
+        "1" | "true" | "on" | "yes" => Some(true),
+        "0" | "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+// Accepts "1.2" or "1.2.0".
+pub fn parse_api_version(s: &str) -> Option<u32> {
+    let mut parts = s.split('.').map(|p| p.parse::<u32>());
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = match parts.next() {
+        Some(p) => p.ok()?,
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(vk::make_api_version(0, major, minor, patch))
+}
+
+pub fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let digits: Vec<u8> = s
+        .chars()
+        .filter(|&c| c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    if digits.len() != 32 {
+        return None;
+    }
+    let mut uuid = [0u8; 16];
+    for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+        *byte = pair[0] << 4 | pair[1];
+    }
+    Some(uuid)
+}
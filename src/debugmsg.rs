@@ -0,0 +1,216 @@
+use crate::loaders::*;
+use crate::handle::*;
+use crate::util::*;
+
+use anyhow::{Context, Result};
+use ash::vk;
+use log::Level;
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::os::raw::{c_char, c_void};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+
+// Running totals of every message the validation layers have sent through our messenger,
+// split by severity. Lives in an `Arc` on `Loaders` so the callback can hold a stable pointer,
+// and so the totals can still be read once the instance has been destroyed.
+#[derive(Default)]
+pub struct DebugMessageCounts {
+    pub verbose: AtomicUsize,
+    pub info: AtomicUsize,
+    pub warning: AtomicUsize,
+    pub error: AtomicUsize,
+}
+
+impl DebugMessageCounts {
+    pub fn verbose(&self) -> usize {
+        self.verbose.load(Ordering::Relaxed)
+    }
+
+    pub fn info(&self) -> usize {
+        self.info.load(Ordering::Relaxed)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.warning.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> usize {
+        self.error.load(Ordering::Relaxed)
+    }
+}
+
+// Create info for our messenger. Used both for the real messenger and chained onto
+// `InstanceCreateInfo` so instance creation and destruction get reported too.
+pub fn messenger_create_info(counts: &DebugMessageCounts) -> vk::DebugUtilsMessengerCreateInfoEXT {
+    vk::DebugUtilsMessengerCreateInfoEXT::default()
+        .message_severity(
+            vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE
+                | vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+        )
+        .message_type(
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+        )
+        .pfn_user_callback(Some(debug_messenger_callback))
+        .user_data(counts as *const DebugMessageCounts as *mut c_void)
+}
+
+fn severity_level(severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> Level {
+    if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        Level::Error
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        Level::Warn
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        Level::Info
+    } else {
+        Level::Trace
+    }
+}
+
+fn type_name(types: vk::DebugUtilsMessageTypeFlagsEXT) -> &'static str {
+    if types.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION) {
+        "validation"
+    } else if types.contains(vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE) {
+        "performance"
+    } else {
+        "general"
+    }
+}
+
+unsafe fn cstr_or<'a>(ptr: *const c_char, fallback: &'a str) -> std::borrow::Cow<'a, str> {
+    if ptr.is_null() {
+        fallback.into()
+    } else {
+        CStr::from_ptr(ptr).to_string_lossy()
+    }
+}
+
+// Formats one callback payload, including the named objects and any queue or
+// command buffer label regions that were open when the message fired.
+pub unsafe fn format_message(
+    types: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: &vk::DebugUtilsMessengerCallbackDataEXT,
+) -> String {
+    let mut text = format!(
+        "[{}] {} ({:#x}): {}",
+        type_name(types),
+        cstr_or(data.p_message_id_name, "-"),
+        data.message_id_number,
+        cstr_or(data.p_message, "<no message>")
+    );
+
+    for obj in raw_slice(data.p_objects, data.object_count) {
+        let _ = write!(text, "\n    object {:?} {:#x}", obj.object_type, obj.object_handle);
+        if !obj.p_object_name.is_null() {
+            let _ = write!(text, " \"{}\"", cstr_or(obj.p_object_name, ""));
+        }
+    }
+
+    for label in raw_slice(data.p_queue_labels, data.queue_label_count) {
+        let _ = write!(text, "\n    in queue label \"{}\"", cstr_or(label.p_label_name, ""));
+    }
+
+    for label in raw_slice(data.p_cmd_buf_labels, data.cmd_buf_label_count) {
+        let _ = write!(text, "\n    in command label \"{}\"", cstr_or(label.p_label_name, ""));
+    }
+
+    text
+}
+
+unsafe extern "system" fn debug_messenger_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    types: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    if let Some(counts) = (user_data as *const DebugMessageCounts).as_ref() {
+        let counter = match severity_level(severity) {
+            Level::Error => &counts.error,
+            Level::Warn => &counts.warning,
+            Level::Info => &counts.info,
+            _ => &counts.verbose,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if let Some(data) = data.as_ref() {
+        log::log!(target: "vulkan", severity_level(severity), "{}", format_message(types, data));
+    }
+
+    // Returning true would abort the call that triggered the message, which only layer tests want.
+    vk::FALSE
+}
+
+#[derive(Clone, Debug)]
+pub struct CapturedMessage {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub text: String,
+}
+
+// A second messenger that records every warning and error, for code that needs to assert the
+// validation layers stayed quiet. The messenger holds the Loaders alive so it is always destroyed
+// before the instance, and is declared first so it goes before the buffer its callback writes to.
+pub struct MessageCapture {
+    pub messenger: Owned<vk::DebugUtilsMessengerEXT, Loaders>,
+    pub messages: Box<Mutex<Vec<CapturedMessage>>>,
+}
+
+impl MessageCapture {
+    pub unsafe fn install(loaders: Rc<Loaders>) -> Result<MessageCapture> {
+        let debug_utils = loaders
+            .debug_utils
+            .as_ref()
+            .context("Debug utils is not enabled, so validation messages can't be captured")?;
+
+        let messages = Box::new(Mutex::new(Vec::new()));
+        let info = vk::DebugUtilsMessengerCreateInfoEXT::default()
+            .message_severity(
+                vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            )
+            .message_type(
+                vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                    | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                    | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+            )
+            .pfn_user_callback(Some(capture_callback))
+            .user_data(&*messages as *const Mutex<Vec<CapturedMessage>> as *mut c_void);
+
+        let messenger = debug_utils
+            .create_debug_utils_messenger(&info, loaders.allocation_callbacks())
+            .context("Could not create capturing debug messenger")?;
+
+        Ok(MessageCapture {
+            messenger: Owned::new(loaders, messenger),
+            messages,
+        })
+    }
+
+    pub fn messages(&self) -> Vec<CapturedMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+unsafe extern "system" fn capture_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    types: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    let messages = (user_data as *const Mutex<Vec<CapturedMessage>>).as_ref();
+    if let (Some(messages), Some(data)) = (messages, data.as_ref()) {
+        if let Ok(mut messages) = messages.lock() {
+            messages.push(CapturedMessage {
+                severity,
+                text: format_message(types, data),
+            });
+        }
+    }
+    vk::FALSE
+}
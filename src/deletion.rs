@@ -0,0 +1,75 @@
+use crate::device::*;
+use crate::handle::*;
+
+use std::collections::VecDeque;
+
+
+// Destroys objects once the GPU is done with them, rather than waiting for the whole device to
+// go idle. Anything retired is tagged with the value of the latest submit at the time, and is
+// destroyed once no submission up to that value can still be running. See timeline.rs. Lives in
+// a RefCell on Device; Owned handles come here when they drop.
+
+pub struct DeletionQueue {
+    // In the order they were retired, which is also the order they're destroyed in.
+    deferred: VecDeque<(u64, Box<dyn FnOnce(&Device)>)>,
+}
+
+impl DeletionQueue {
+    pub fn new() -> Self {
+        DeletionQueue {
+            deferred: VecDeque::new(),
+        }
+    }
+
+    // Takes out everything that's safe to run now, so it runs without the queue borrowed.
+    fn take_ready(&mut self, oldest_pending: u64) -> Vec<Box<dyn FnOnce(&Device)>> {
+        let mut ready = Vec::new();
+        while self.deferred.front().map_or(false, |&(value, _)| value < oldest_pending) {
+            ready.push(self.deferred.pop_front().unwrap().1);
+        }
+        ready
+    }
+
+    fn take_all(&mut self) -> Vec<Box<dyn FnOnce(&Device)>> {
+        self.deferred.drain(..).map(|(_, f)| f).collect()
+    }
+}
+
+impl Device {
+    // Runs f once everything submitted so far has finished.
+    pub fn defer(&self, f: impl FnOnce(&Device) + 'static) {
+        let value = self.last_submission();
+        self.deletion.borrow_mut().deferred.push_back((value, Box::new(f)));
+    }
+
+    pub fn defer_destroy<T: Destroy<Device> + 'static>(&self, handle: T) {
+        self.defer(move |device| unsafe { handle.destroy(device) });
+    }
+
+    // Checks the submissions nobody is waiting on, then destroys whatever is no longer in use.
+    // Cheap enough to call every frame.
+    pub fn collect_garbage(&self) {
+        self.poll_submissions();
+        let oldest_pending = self.oldest_pending_submission();
+        let ready = self.deletion.borrow_mut().take_ready(oldest_pending);
+        for f in ready {
+            f(self);
+        }
+    }
+
+    // Waits for the device to go idle and destroys everything. For device loss and teardown.
+    pub fn flush_deletions(&self) {
+        unsafe {
+            let _ = self.device.device_wait_idle();
+        }
+        self.forget_submissions();
+        let all = self.deletion.borrow_mut().take_all();
+        for f in all {
+            f(self);
+        }
+    }
+
+    pub fn deferred_count(&self) -> usize {
+        self.deletion.borrow().deferred.len()
+    }
+}
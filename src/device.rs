@@ -1,18 +1,63 @@
 use crate::util::*;
 use crate::config::*;
+use crate::features::*;
 use crate::loaders::*;
+use crate::memory::*;
+use crate::upload::*;
+use crate::deletion::*;
+use crate::pipelinecache::*;
+use crate::registry::*;
+use crate::timeline::*;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use ash::extensions::ext::DebugUtils;
 use ash::extensions::khr::Swapchain;
 
 use ash::vk::{self, SurfaceKHR};
-use std::collections::{BTreeMap, HashSet};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
 use std::default::Default;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::fmt;
 use std::rc::Rc;
 
 
+// Label regions for captures and validation messages. Without the debug-labels feature every
+// label call is a constant false check the compiler removes; with it they still do nothing when
+// debug utils isn't enabled. Names are only formatted when a label is actually recorded, so
+// callers can pass format_args!() without paying for it otherwise.
+const LABELS_ENABLED: bool = cfg!(feature = "debug-labels");
+
+#[derive(Clone, Copy)]
+pub struct CmdLabels<'a> {
+    debug_utils: Option<&'a DebugUtils>,
+}
+
+impl CmdLabels<'_> {
+    fn label(name: impl fmt::Display, color: [f32; 4], record: impl FnOnce(&vk::DebugUtilsLabelEXT)) {
+        if let Ok(name) = CString::new(name.to_string()) {
+            record(&vk::DebugUtilsLabelEXT {
+                p_label_name: name.as_ptr(),
+                color,
+                ..Default::default()
+            });
+        }
+    }
+
+    pub fn begin(&self, command_buffer: vk::CommandBuffer, name: impl fmt::Display, color: [f32; 4]) {
+        if let Some(debug_utils) = self.debug_utils {
+            Self::label(name, color, |label| unsafe { debug_utils.cmd_begin_debug_utils_label(command_buffer, label) });
+        }
+    }
+
+    pub fn end(&self, command_buffer: vk::CommandBuffer) {
+        if let Some(debug_utils) = self.debug_utils {
+            unsafe { debug_utils.cmd_end_debug_utils_label(command_buffer) };
+        }
+    }
+}
+
 pub struct Device {
     pub loaders: Rc<Loaders>,
     pub device: ash::Device,
@@ -21,151 +66,271 @@ pub struct Device {
     pub graphics_queue: vk::Queue,
     pub present_queue_family: u32,
     pub present_queue: vk::Queue,
-    pub command_pool: vk::CommandPool,
+    // The graphics family and queue again when the device has no separate family for these.
+    pub transfer_queue_family: u32,
+    pub transfer_queue: vk::Queue,
+    pub transfer_command_pool: vk::CommandPool,
+    pub compute_queue_family: u32,
+    pub compute_queue: vk::Queue,
+    pub compute_command_pool: vk::CommandPool,
     pub swapchain_loader: ash::extensions::khr::Swapchain,
+    pub headless: bool,
+    pub config: DeviceConfig,
+    pub api_version: u32,
+    // Everything that was enabled, required or optional, so code can branch on optional ones.
+    pub features: DeviceFeatures,
+    pub enabled_extensions: Vec<CString>,
+    pub allocator: RefCell<Allocator>,
+    pub uploads: RefCell<UploadContext>,
+    pub deletion: RefCell<DeletionQueue>,
+    pub timeline: RefCell<GpuTimeline>,
+    // Shared by every pipeline, and saved to config.pipeline_cache when the device goes.
+    pub pipeline_cache: vk::PipelineCache,
 }
 
 impl Device {
-    pub unsafe fn create(loaders: Rc<Loaders>, surface: SurfaceKHR) -> Result<Device> {
-        let required_device_extensions = [(vk::KhrSwapchainFn::name(), 0u32)];
+    pub unsafe fn create(
+        loaders: Rc<Loaders>,
+        surface: SurfaceKHR,
+        config: DeviceConfig,
+    ) -> Result<Device> {
+        Self::create_impl(loaders, Some(surface), config)
+    }
+
+    // Graphics-only device with no swapchain support. The present queue is just the graphics
+    // queue.
+    pub unsafe fn create_headless(loaders: Rc<Loaders>, config: DeviceConfig) -> Result<Device> {
+        Self::create_impl(loaders, None, config)
+    }
+
+    unsafe fn create_impl(
+        loaders: Rc<Loaders>,
+        surface: Option<SurfaceKHR>,
+        config: DeviceConfig,
+    ) -> Result<Device> {
+        let mut requested_extensions = Vec::<Requested>::new();
+        if surface.is_some() {
+            requested_extensions.push(Requested::required(&vk::KhrSwapchainFn::name().to_string_lossy()));
+        }
+        // The budget is read through vkGetPhysicalDeviceMemoryProperties2, and the extension
+        // depends on it, so it needs a 1.1 instance. memory_stats() also checks the device is 1.1.
+        if loaders.supports_api(1, 1) {
+            requested_extensions.push(Requested::optional(&vk::ExtMemoryBudgetFn::name().to_string_lossy()));
+        }
+        requested_extensions.extend(config.extensions.iter().cloned());
 
-        let required_device_extensions_raw = required_device_extensions
+        // A misspelt feature would otherwise just get every device rejected.
+        let unknown_features: Vec<&str> = config
+            .features
             .iter()
-            .map(|(name, version)| name.as_ptr())
-            .collect::<Vec<*const i8>>();
+            .map(|f| f.name.as_str())
+            .filter(|&name| DeviceFeatures::default().get(name).is_none())
+            .collect();
+        if !unknown_features.is_empty() {
+            bail!("Unknown device features: {}", unknown_features.join(", "));
+        }
 
-        let (physical_device, graphics_queue_family, present_queue_family) = loaders
+        let mut requested_features = config.features.clone();
+        if config.timeline_semaphores {
+            requested_features.push(Requested::optional("timeline_semaphore"));
+        }
+        if config.synchronization2 {
+            requested_features.push(Requested::optional("synchronization2"));
+        }
+
+        let evaluated: Vec<(PhysicalDeviceInfo, Result<DeviceChoice, String>)> = loaders
             .instance
             .enumerate_physical_devices()
-            .unwrap()
-            .iter()
-            .find_map(|&dev| {
-                let props = loaders.instance.get_physical_device_properties(dev);
-                let features = loaders.instance.get_physical_device_features(dev);
-                let queues = loaders
-                    .instance
-                    .get_physical_device_queue_family_properties(dev);
-
-                dbg!(&props);
-                dbg!(&features);
-                dbg!(&queues);
-
-                if props.device_type != vk::PhysicalDeviceType::DISCRETE_GPU {
-                    return None;
+            .context("Could not enumerate physical devices")?
+            .into_iter()
+            .enumerate()
+            .map(|(index, dev)| {
+                let info = PhysicalDeviceInfo::query(&loaders, index, dev);
+                let choice = evaluate_device(&loaders, &info, surface, &requested_extensions, &requested_features);
+                match &choice {
+                    Ok(choice) => log::info!("{}: score {}", info, choice.score),
+                    Err(reason) => log::info!("{}: rejected, {}", info, reason),
                 }
+                (info, choice)
+            })
+            .collect();
 
-                let graphics_queue_family_index = queues
+        let (info, choice) = match &config.device {
+            Some(selector) => {
+                let (info, choice) = evaluated
                     .iter()
-                    .position(|&q| q.queue_flags.contains(vk::QueueFlags::GRAPHICS))?
-                    as u32;
-
-                let present_queue_family_index = (graphics_queue_family_index
-                    ..=graphics_queue_family_index)
-                    .chain(0..queues.len() as u32)
-                    .find(|&i| {
-                        loaders
-                            .surface
-                            .get_physical_device_surface_support(dev, i, surface)
-                            == Ok(true)
-                    })?;
-
-                let raw_extensions =
-                    match loaders.instance.enumerate_device_extension_properties(dev) {
-                        Ok(exts) => exts,
-                        Err(e) => {
-                            return None;
-                        }
-                    };
-
-                let avail_extensions: BTreeMap<&CStr, u32> =
-                    BTreeMap::from_iter(raw_extensions.iter().filter_map(|props| {
-                        Some((safer_cstr(&props.extension_name)?, props.spec_version))
-                    }));
-
-                dbg!(&avail_extensions);
-
-                for req in required_device_extensions {
-                    if let Some(&version) = avail_extensions.get(req.0) {
-                        if version < req.1 {
-                            return None;
-                        }
-                    } else {
-                        return None;
-                    }
+                    .find(|(info, _)| info.matches(selector))
+                    .with_context(|| format!("No physical device matches {:?}", selector))?;
+                match choice {
+                    Ok(choice) => (info, choice),
+                    Err(reason) => bail!("{} was selected by {:?} but can't be used: {}", info, selector, reason),
                 }
+            }
+            None => evaluated
+                .iter()
+                .filter_map(|(info, choice)| Some((info, choice.as_ref().ok()?)))
+                // Earlier devices win ties, so the order the loader reports still means something.
+                .max_by(|(a_info, a), (b_info, b)| a.score.cmp(&b.score).then(b_info.index.cmp(&a_info.index)))
+                .with_context(|| {
+                    let reasons: Vec<String> = evaluated
+                        .iter()
+                        .map(|(info, choice)| format!("{}: {}", info, choice.as_ref().err().map_or("", |r| r.as_str())))
+                        .collect();
+                    format!("Could not find any suitable physical device. {}", reasons.join("; "))
+                })?,
+        };
+        let physical_device = info.physical_device;
+        let graphics_queue_family = choice.graphics_queue_family;
+        let present_queue_family = choice.present_queue_family;
 
-                let capabilities = loaders
-                    .surface
-                    .get_physical_device_surface_capabilities(dev, surface)
-                    .ok()?;
-
-                let formats = loaders
-                    .surface
-                    .get_physical_device_surface_formats(dev, surface)
-                    .ok()?;
-
-                let present_modes = loaders
-                    .surface
-                    .get_physical_device_surface_present_modes(dev, surface)
-                    .ok()?;
-
-                dbg!(&capabilities);
-                dbg!(&formats);
-                dbg!(&present_modes);
-
-                if formats.is_empty() || present_modes.is_empty() {
-                    return None;
-                }
+        let queue_families = loaders
+            .instance
+            .get_physical_device_queue_family_properties(physical_device);
+        let (transfer_queue_family, compute_queue_family) =
+            dedicated_queue_families(&queue_families, graphics_queue_family);
+        log::info!(
+            "Queue families: graphics {}, present {}, transfer {}, compute {}",
+            graphics_queue_family,
+            present_queue_family,
+            transfer_queue_family,
+            compute_queue_family
+        );
 
-                Some((dev, graphics_queue_family_index, present_queue_family_index))
-            })
-            .ok_or(vk::Result::ERROR_DEVICE_LOST)
-            .context("Could not find any suitable physical device")?;
+        let queue_infos: Vec<vk::DeviceQueueCreateInfo> = HashSet::from([
+            graphics_queue_family,
+            present_queue_family,
+            transfer_queue_family,
+            compute_queue_family,
+        ])
+        .iter()
+        .map(|&family_index| {
+            vk::DeviceQueueCreateInfo::default()
+                .queue_family_index(family_index)
+                .queue_priorities(&[1.0])
+        })
+        .collect();
 
-        let queue_infos: Vec<vk::DeviceQueueCreateInfo> =
-            HashSet::from([graphics_queue_family, present_queue_family])
-                .iter()
-                .map(|&family_index| {
-                    vk::DeviceQueueCreateInfo::default()
-                        .queue_family_index(family_index)
-                        .queue_priorities(&[1.0])
-                })
-                .collect();
+        // What we can actually use is capped by both the instance and this particular device.
+        let device_props = loaders.instance.get_physical_device_properties(physical_device);
+        let api_version = api_version_min(loaders.api_version, device_props.api_version);
+        log::info!(
+            "Using {} with Vulkan {} (device supports {})",
+            cstr_string(&device_props.device_name),
+            version_string(api_version),
+            version_string(device_props.api_version)
+        );
 
-        let needed_features = vk::PhysicalDeviceFeatures::default();
-        let mut layers = Vec::<CString>::new();
-        if VK_DEBUG_LAYER {
-            layers.push(CString::new("VK_LAYER_KHRONOS_validation").unwrap());
+        let enabled_extensions = choice.extensions.clone();
+        let mut features = choice.features;
+        log::info!("Device extensions: {:?}", enabled_extensions);
+        for req in config.extensions.iter().filter(|r| !r.required) {
+            if !enabled_extensions.iter().any(|e| e.to_string_lossy() == req.name) {
+                log::warn!("Optional device extension {} is not available, skipping it", req.name);
+            }
+        }
+        for req in config.features.iter() {
+            if features.get(&req.name) != Some(true) {
+                log::warn!("Optional device feature {} is not available, skipping it", req.name);
+            }
         }
-        let layers_raw: Vec<*const i8> = layers.iter().map(|c| c.as_ptr()).collect();
 
+        // Device layers are deprecated, but older loaders still want them to match the instance.
+        let layers_raw: Vec<*const i8> = loaders.enabled_layers.iter().map(|c| c.as_ptr()).collect();
+        let extensions_raw: Vec<*const i8> = enabled_extensions.iter().map(|c| c.as_ptr()).collect();
+
+        let mut features2 = features.chain(api_version);
         let device_info = vk::DeviceCreateInfo::default()
             .queue_create_infos(&queue_infos)
-            .enabled_features(&needed_features)
             .enabled_layer_names(&layers_raw)
-            .enabled_extension_names(&required_device_extensions_raw); // .enabled_extension_names(&extensions_raw); -- all of these are layer-level not device-level
+            .enabled_extension_names(&extensions_raw);
+        // Anything past the core features needs the whole chain, which replaces pEnabledFeatures.
+        let device_info = if api_version >= vk::make_api_version(0, 1, 2, 0) {
+            device_info.push_next(&mut features2)
+        } else {
+            device_info.enabled_features(&features2.features)
+        };
 
         let device = loaders
             .instance
-            .create_device(physical_device, &device_info, None)
-            .context("Could not create logical device")?;
+            .create_device(physical_device, &device_info, loaders.allocation_callbacks())
+            .context("Could not create logical device");
+        features.unchain();
+        let device = device?;
+
+        let initial_data = config
+            .pipeline_cache
+            .as_deref()
+            .and_then(|path| load_pipeline_cache(path, &device_props))
+            .unwrap_or_default();
+        let create_cache = |data: &[u8]| {
+            device.create_pipeline_cache(
+                &vk::PipelineCacheCreateInfo::default().initial_data(data),
+                loaders.allocation_callbacks(),
+            )
+        };
+        // The header checks should catch anything stale, but a driver can still refuse the data.
+        let pipeline_cache = match create_cache(&initial_data) {
+            Err(e) if !initial_data.is_empty() => {
+                log::warn!("The driver rejected the saved pipeline cache ({}), starting an empty one", e);
+                create_cache(&[])
+            }
+            result => result,
+        };
+        let pipeline_cache = match pipeline_cache {
+            Ok(pipeline_cache) => pipeline_cache,
+            Err(e) => {
+                device.destroy_device(loaders.allocation_callbacks());
+                return Err(e).context("Could not create pipeline cache");
+            }
+        };
 
         let graphics_queue = device.get_device_queue(graphics_queue_family, 0);
 
         let present_queue = device.get_device_queue(present_queue_family, 0);
 
-        let command_pool = device
-            .create_command_pool(
+        let transfer_queue = device.get_device_queue(transfer_queue_family, 0);
+
+        let compute_queue = device.get_device_queue(compute_queue_family, 0);
+
+        // One pool per role, even when roles share a family, so each can be recorded and reset
+        // on its own. Graphics work is recorded from each frame's own pools, see PerFrame.
+        let pools: Vec<_> = [
+            (
+                transfer_queue_family,
+                vk::CommandPoolCreateFlags::TRANSIENT | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            ),
+            (compute_queue_family, vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
+        ]
+        .iter()
+        .map(|&(family, flags)| {
+            device.create_command_pool(
                 &vk::CommandPoolCreateInfo::default()
-                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-                    .queue_family_index(graphics_queue_family),
-                None,
+                    .flags(flags)
+                    .queue_family_index(family),
+                loaders.allocation_callbacks(),
             )
-            .context("Could not create command pool for device")?;
+        })
+        .collect();
+
+        if let Some(&Err(e)) = pools.iter().find(|p| p.is_err()) {
+            for &pool in pools.iter().flatten() {
+                device.destroy_command_pool(pool, loaders.allocation_callbacks());
+            }
+            device.destroy_pipeline_cache(pipeline_cache, loaders.allocation_callbacks());
+            device.destroy_device(loaders.allocation_callbacks());
+            return Err(e).context("Could not create command pools for device");
+        }
+        let (transfer_command_pool, compute_command_pool) = (pools[0].unwrap(), pools[1].unwrap());
+
+        let allocator = Allocator::new(
+            loaders.instance.get_physical_device_memory_properties(physical_device),
+            &device_props.limits,
+        );
 
         let swapchain_loader = Swapchain::new(&loaders.instance, &device);
+        let headless = surface.is_none();
 
-        Ok(Device {
+        let result = Device {
             loaders,
             device,
             physical_device,
@@ -173,17 +338,435 @@ impl Device {
             graphics_queue,
             present_queue_family,
             present_queue,
-            command_pool,
+            transfer_queue_family,
+            transfer_queue,
+            transfer_command_pool,
+            compute_queue_family,
+            compute_queue,
+            compute_command_pool,
             swapchain_loader,
-        })
+            headless,
+            config,
+            api_version,
+            features,
+            enabled_extensions,
+            allocator: RefCell::new(allocator),
+            uploads: RefCell::new(UploadContext::new()),
+            deletion: RefCell::new(DeletionQueue::new()),
+            timeline: RefCell::new(GpuTimeline::new()),
+            pipeline_cache,
+        };
+
+        register_object(&result, result.transfer_command_pool);
+        register_object(&result, result.compute_command_pool);
+        register_object(&result, result.pipeline_cache);
+
+        result.set_name(result.device.handle(), "device");
+        result.set_name(result.graphics_queue, "graphics queue");
+        if result.present_queue != result.graphics_queue {
+            result.set_name(result.present_queue, "present queue");
+        }
+        if result.transfer_queue != result.graphics_queue {
+            result.set_name(result.transfer_queue, "transfer queue");
+        }
+        if result.compute_queue != result.graphics_queue && result.compute_queue != result.transfer_queue {
+            result.set_name(result.compute_queue, "compute queue");
+        }
+        result.set_name(result.transfer_command_pool, "transfer command pool");
+        result.set_name(result.compute_command_pool, "compute command pool");
+        result.set_name(result.pipeline_cache, "pipeline cache");
+
+        if result.config.timeline_semaphores && result.has_feature("timeline_semaphore") {
+            result.create_timelines().context("Could not create timeline semaphores")?;
+            log::info!("Tracking submissions with timeline semaphores");
+        } else {
+            log::info!("Tracking submissions with fences");
+        }
+
+        Ok(result)
+    }
+
+    // Gives the object a readable name in validation messages and captures. Does nothing when
+    // debug utils isn't enabled.
+    pub fn set_name<H: vk::Handle>(&self, handle: H, name: &str) {
+        name_object(self, handle, name);
+        let debug_utils = match &self.loaders.debug_utils {
+            Some(debug_utils) => debug_utils,
+            None => return,
+        };
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        let name_info = vk::DebugUtilsObjectNameInfoEXT {
+            object_type: H::TYPE,
+            object_handle: handle.as_raw(),
+            p_object_name: name.as_ptr(),
+            ..Default::default()
+        };
+        unsafe {
+            let _ = debug_utils.set_debug_utils_object_name(self.device.handle(), &name_info);
+        }
+    }
+
+    // Label commands for recording into command buffers, which can be handed to worker threads.
+    pub fn cmd_labels(&self) -> CmdLabels<'_> {
+        CmdLabels {
+            debug_utils: if LABELS_ENABLED { self.loaders.debug_utils.as_ref() } else { None },
+        }
+    }
+
+    pub fn queue_begin_label(&self, queue: vk::Queue, name: impl fmt::Display) {
+        if !LABELS_ENABLED {
+            return;
+        }
+        if let Some(debug_utils) = &self.loaders.debug_utils {
+            if let Ok(name) = CString::new(name.to_string()) {
+                let label = vk::DebugUtilsLabelEXT {
+                    p_label_name: name.as_ptr(),
+                    ..Default::default()
+                };
+                unsafe { debug_utils.queue_begin_debug_utils_label(queue, &label) };
+            }
+        }
+    }
+
+    pub fn queue_end_label(&self, queue: vk::Queue) {
+        if !LABELS_ENABLED {
+            return;
+        }
+        if let Some(debug_utils) = &self.loaders.debug_utils {
+            unsafe { debug_utils.queue_end_debug_utils_label(queue) };
+        }
+    }
+
+    // Every queue we created, graphics first. A family appears once even if it has several roles.
+    pub fn queues(&self) -> Vec<(u32, vk::Queue)> {
+        let mut queues = vec![(self.graphics_queue_family, self.graphics_queue)];
+        for (family, queue) in [
+            (self.present_queue_family, self.present_queue),
+            (self.transfer_queue_family, self.transfer_queue),
+            (self.compute_queue_family, self.compute_queue),
+        ] {
+            if !queues.iter().any(|&(f, _)| f == family) {
+                queues.push((family, queue));
+            }
+        }
+        queues
+    }
+
+    // Picks the queue to present to a surface with, which needn't be the surface the device was
+    // created for. Only families we created a queue in can be used, and the graphics family is
+    // preferred so the swapchain images stay exclusive.
+    pub unsafe fn present_queue_for(&self, surface: SurfaceKHR) -> Result<(u32, vk::Queue)> {
+        let supports = |family: u32| {
+            self.loaders
+                .surface
+                .get_physical_device_surface_support(self.physical_device, family, surface)
+                .context("Could not query surface present support")
+        };
+
+        for (family, queue) in self.queues() {
+            if supports(family)? {
+                return Ok((family, queue));
+            }
+        }
+
+        let family_count = self
+            .loaders
+            .instance
+            .get_physical_device_queue_family_properties(self.physical_device)
+            .len() as u32;
+        for family in 0..family_count {
+            if supports(family)? {
+                bail!(
+                    "Only queue family {} can present to this surface, and the device has no queue there",
+                    family
+                );
+            }
+        }
+        bail!("No queue family on this device can present to this surface")
+    }
+
+    pub fn allocation_callbacks(&self) -> Option<&vk::AllocationCallbacks<'static>> {
+        self.loaders.allocation_callbacks()
+    }
+
+    // Writes the pipeline cache back to its file. Happens when the device is dropped, but can be
+    // done sooner so a crash doesn't lose it.
+    pub fn save_pipeline_cache(&self) {
+        let path = match &self.config.pipeline_cache {
+            Some(path) => path,
+            None => return,
+        };
+        let saved = unsafe { self.device.get_pipeline_cache_data(self.pipeline_cache) }
+            .context("Could not get pipeline cache data")
+            .and_then(|data| {
+                save_pipeline_cache(path, &data).context("Could not write the file")?;
+                Ok(data.len())
+            });
+        match saved {
+            Ok(size) => log::info!("Saved {} bytes of pipeline cache to {}", size, path.display()),
+            Err(e) => log::warn!("Could not save pipeline cache to {}: {:?}", path.display(), e),
+        }
+    }
+
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.get(name) == Some(true)
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.enabled_extensions.iter().any(|e| e.as_c_str() == name)
+    }
+
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.transfer_queue_family != self.graphics_queue_family
+    }
+
+    pub fn has_async_compute(&self) -> bool {
+        self.compute_queue_family != self.graphics_queue_family
+    }
+
+    // Check this before relying on anything that was promoted to core in a later version.
+    pub fn supports_api(&self, major: u32, minor: u32) -> bool {
+        self.api_version >= vk::make_api_version(0, major, minor, 0)
     }
 }
 
 impl Drop for Device {
     fn drop(&mut self) {
         unsafe {
-            self.device.destroy_command_pool(self.command_pool, None);
-            self.device.destroy_device(None);
+            self.flush_deletions();
+            self.save_pipeline_cache();
+            self.timeline.borrow_mut().destroy(self);
+            unregister_object(self, self.pipeline_cache);
+            self.device.destroy_pipeline_cache(self.pipeline_cache, self.allocation_callbacks());
+            self.uploads.borrow_mut().destroy(self);
+            self.allocator.borrow_mut().destroy(self);
+            for pool in [self.compute_command_pool, self.transfer_command_pool] {
+                unregister_object(self, pool);
+                self.device.destroy_command_pool(pool, self.allocation_callbacks());
+            }
+            self.device.destroy_device(self.allocation_callbacks());
+        }
+        report_live_objects(self, "device");
+    }
+}
+
+// What we show about a physical device when choosing one, and what a DeviceSelector matches on.
+pub struct PhysicalDeviceInfo {
+    pub index: usize,
+    pub physical_device: vk::PhysicalDevice,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    // Needs Vulkan 1.1 on both the instance and the device.
+    pub uuid: Option<[u8; 16]>,
+}
+
+impl PhysicalDeviceInfo {
+    pub unsafe fn query(loaders: &Loaders, index: usize, physical_device: vk::PhysicalDevice) -> Self {
+        let props = loaders.instance.get_physical_device_properties(physical_device);
+        let uuid = if loaders.supports_api(1, 1) && props.api_version >= vk::make_api_version(0, 1, 1, 0) {
+            let mut id_props = vk::PhysicalDeviceIDProperties::default();
+            let mut props2 = vk::PhysicalDeviceProperties2::default().push_next(&mut id_props);
+            loaders.instance.get_physical_device_properties2(physical_device, &mut props2);
+            Some(id_props.device_uuid)
+        } else {
+            None
+        };
+
+        PhysicalDeviceInfo {
+            index,
+            physical_device,
+            name: cstr_string(&props.device_name),
+            device_type: props.device_type,
+            api_version: props.api_version,
+            uuid,
         }
     }
+
+    pub fn matches(&self, selector: &DeviceSelector) -> bool {
+        match selector {
+            DeviceSelector::Index(index) => self.index == *index,
+            DeviceSelector::Name(name) => self.name.to_lowercase().contains(&name.to_lowercase()),
+            DeviceSelector::Uuid(uuid) => self.uuid.as_ref() == Some(uuid),
+        }
+    }
+}
+
+impl fmt::Display for PhysicalDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Device {} \"{}\" ({:?}, Vulkan {}",
+            self.index,
+            self.name,
+            self.device_type,
+            version_string(self.api_version)
+        )?;
+        if let Some(uuid) = &self.uuid {
+            write!(f, ", UUID {}", uuid_string(uuid))?;
+        }
+        write!(f, ")")
+    }
+}
+
+pub struct DeviceChoice {
+    pub score: u64,
+    pub graphics_queue_family: u32,
+    pub present_queue_family: u32,
+    pub extensions: Vec<CString>,
+    pub features: DeviceFeatures,
+}
+
+// A transfer-only family is usually a DMA engine that copies while the graphics queue draws,
+// and a compute family without graphics runs alongside it. Falls back to the graphics family
+// for either one that doesn't exist. Returns (transfer, compute).
+pub fn dedicated_queue_families(families: &[vk::QueueFamilyProperties], graphics_family: u32) -> (u32, u32) {
+    let find = |wanted: vk::QueueFlags, unwanted: vk::QueueFlags| {
+        families
+            .iter()
+            .position(|f| f.queue_count > 0 && f.queue_flags.contains(wanted) && !f.queue_flags.intersects(unwanted))
+            .map(|i| i as u32)
+    };
+
+    let compute = find(vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS).unwrap_or(graphics_family);
+    let transfer = find(vk::QueueFlags::TRANSFER, vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        .unwrap_or(graphics_family);
+    (transfer, compute)
+}
+
+// Device type dominates, so a discrete GPU always beats an integrated one, which always beats
+// a CPU implementation. Within a type, newer core API and then more device-local memory win.
+// Each optional extension or feature the device has adds a point on top of the memory's, and
+// between them they stay below one step in API version.
+fn device_score(
+    device_type: vk::PhysicalDeviceType,
+    api_version: u32,
+    memory: &vk::PhysicalDeviceMemoryProperties,
+    optional_count: usize,
+) -> u64 {
+    let type_score = match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4000,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3000,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2000,
+        vk::PhysicalDeviceType::CPU => 1000,
+        _ => 0,
+    };
+
+    let api_score = vk::api_version_minor(api_version).min(9) as u64 * 100;
+
+    let device_local_bytes: u64 = memory.memory_heaps[..memory.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum();
+    let bonus = ((device_local_bytes >> 30) + optional_count as u64).min(99);
+
+    type_score + api_score + bonus
+}
+
+// Err is the reason the device can't be used, for the log.
+unsafe fn evaluate_device(
+    loaders: &Loaders,
+    info: &PhysicalDeviceInfo,
+    surface: Option<SurfaceKHR>,
+    requested_extensions: &[Requested],
+    requested_features: &[Requested],
+) -> Result<DeviceChoice, String> {
+    let dev = info.physical_device;
+    let queues = loaders.instance.get_physical_device_queue_family_properties(dev);
+
+    let graphics_queue_family = queues
+        .iter()
+        .position(|q| q.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+        .ok_or("no graphics queue family")? as u32;
+
+    let raw_extensions = loaders
+        .instance
+        .enumerate_device_extension_properties(dev)
+        .map_err(|e| format!("could not enumerate extensions ({})", e))?;
+    let available_extensions: BTreeSet<CString> = raw_extensions
+        .iter()
+        .filter_map(|props| safer_cstr(&props.extension_name).map(CString::from))
+        .collect();
+
+    let mut extensions = Vec::<CString>::new();
+    let mut missing = Vec::<&str>::new();
+    for req in requested_extensions {
+        match CString::new(req.name.as_str()) {
+            Ok(name) if available_extensions.contains(&name) => {
+                if !extensions.contains(&name) {
+                    extensions.push(name);
+                }
+            }
+            _ if req.required => missing.push(&req.name),
+            _ => {}
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!("missing extensions {}", missing.join(", ")));
+    }
+
+    let api_version = api_version_min(loaders.api_version, info.api_version);
+    let supported = DeviceFeatures::query(&loaders.instance, dev, api_version);
+    let mut features = DeviceFeatures::default();
+    let mut missing = Vec::<&str>::new();
+    for req in requested_features {
+        if supported.get(&req.name) == Some(true) {
+            features.set(&req.name, true);
+        } else if req.required {
+            missing.push(&req.name);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!("missing features {}", missing.join(", ")));
+    }
+
+    let present_queue_family = match surface {
+        Some(surface) => {
+            let present_queue_family = (graphics_queue_family..=graphics_queue_family)
+                .chain(0..queues.len() as u32)
+                .find(|&i| loaders.surface.get_physical_device_surface_support(dev, i, surface) == Ok(true))
+                .ok_or("no queue family can present to the surface")?;
+
+            loaders
+                .surface
+                .get_physical_device_surface_capabilities(dev, surface)
+                .map_err(|e| format!("could not get surface capabilities ({})", e))?;
+            let formats = loaders
+                .surface
+                .get_physical_device_surface_formats(dev, surface)
+                .map_err(|e| format!("could not get surface formats ({})", e))?;
+            let present_modes = loaders
+                .surface
+                .get_physical_device_surface_present_modes(dev, surface)
+                .map_err(|e| format!("could not get present modes ({})", e))?;
+            if formats.is_empty() || present_modes.is_empty() {
+                return Err("the surface has no formats or present modes".to_string());
+            }
+
+            present_queue_family
+        }
+        None => graphics_queue_family,
+    };
+
+    let optional_count = requested_extensions
+        .iter()
+        .filter(|r| !r.required && extensions.iter().any(|e| e.to_string_lossy() == r.name))
+        .count()
+        + requested_features
+            .iter()
+            .filter(|r| !r.required && features.get(&r.name) == Some(true))
+            .count();
+
+    let memory = loaders.instance.get_physical_device_memory_properties(dev);
+    Ok(DeviceChoice {
+        score: device_score(info.device_type, info.api_version, &memory, optional_count),
+        graphics_queue_family,
+        present_queue_family,
+        extensions,
+        features,
+    })
 }
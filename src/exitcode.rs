@@ -0,0 +1,6 @@
+// Shared with the integration tests, which include this file with #[path] since they can't use
+// the binary's modules.
+
+// What --selftest exits with when this machine can't run it, so the integration test can tell
+// that apart from a failure. 77 is the autotools convention for a skipped test.
+pub const SKIPPED_EXIT_CODE: i32 = 77;
@@ -0,0 +1,239 @@
+use ash::vk;
+use std::os::raw::c_void;
+
+
+// Name tables for the feature structs, so features can be listed in reports and requested by
+// name without a hand-written match for every Bool32 field.
+
+macro_rules! feature_table {
+    ($list:ident, $set:ident, $ty:ty, [$($field:ident),* $(,)?]) => {
+        pub fn $list(features: &$ty) -> Vec<(&'static str, bool)> {
+            vec![$((stringify!($field), features.$field == vk::TRUE)),*]
+        }
+
+        // Returns false if the name isn't a field of this struct.
+        pub fn $set(features: &mut $ty, name: &str, enable: bool) -> bool {
+            match name {
+                $(stringify!($field) => {
+                    features.$field = if enable { vk::TRUE } else { vk::FALSE };
+                    true
+                })*
+                _ => false,
+            }
+        }
+    };
+}
+
+feature_table!(core_features, set_core_feature, vk::PhysicalDeviceFeatures, [
+    robust_buffer_access,
+    full_draw_index_uint32,
+    image_cube_array,
+    independent_blend,
+    geometry_shader,
+    tessellation_shader,
+    sample_rate_shading,
+    dual_src_blend,
+    logic_op,
+    multi_draw_indirect,
+    draw_indirect_first_instance,
+    depth_clamp,
+    depth_bias_clamp,
+    fill_mode_non_solid,
+    depth_bounds,
+    wide_lines,
+    large_points,
+    alpha_to_one,
+    multi_viewport,
+    sampler_anisotropy,
+    texture_compression_etc2,
+    texture_compression_astc_ldr,
+    texture_compression_bc,
+    occlusion_query_precise,
+    pipeline_statistics_query,
+    vertex_pipeline_stores_and_atomics,
+    fragment_stores_and_atomics,
+    shader_tessellation_and_geometry_point_size,
+    shader_image_gather_extended,
+    shader_storage_image_extended_formats,
+    shader_storage_image_multisample,
+    shader_storage_image_read_without_format,
+    shader_storage_image_write_without_format,
+    shader_uniform_buffer_array_dynamic_indexing,
+    shader_sampled_image_array_dynamic_indexing,
+    shader_storage_buffer_array_dynamic_indexing,
+    shader_storage_image_array_dynamic_indexing,
+    shader_clip_distance,
+    shader_cull_distance,
+    shader_float64,
+    shader_int64,
+    shader_int16,
+    shader_resource_residency,
+    shader_resource_min_lod,
+    sparse_binding,
+    sparse_residency_buffer,
+    sparse_residency_image2_d,
+    sparse_residency_image3_d,
+    sparse_residency2_samples,
+    sparse_residency4_samples,
+    sparse_residency8_samples,
+    sparse_residency16_samples,
+    sparse_residency_aliased,
+    variable_multisample_rate,
+    inherited_queries,
+]);
+
+feature_table!(vulkan11_features, set_vulkan11_feature, vk::PhysicalDeviceVulkan11Features<'_>, [
+    storage_buffer16_bit_access,
+    uniform_and_storage_buffer16_bit_access,
+    storage_push_constant16,
+    storage_input_output16,
+    multiview,
+    multiview_geometry_shader,
+    multiview_tessellation_shader,
+    variable_pointers_storage_buffer,
+    variable_pointers,
+    protected_memory,
+    sampler_ycbcr_conversion,
+    shader_draw_parameters,
+]);
+
+feature_table!(vulkan12_features, set_vulkan12_feature, vk::PhysicalDeviceVulkan12Features<'_>, [
+    sampler_mirror_clamp_to_edge,
+    draw_indirect_count,
+    storage_buffer8_bit_access,
+    uniform_and_storage_buffer8_bit_access,
+    storage_push_constant8,
+    shader_buffer_int64_atomics,
+    shader_shared_int64_atomics,
+    shader_float16,
+    shader_int8,
+    descriptor_indexing,
+    shader_input_attachment_array_dynamic_indexing,
+    shader_uniform_texel_buffer_array_dynamic_indexing,
+    shader_storage_texel_buffer_array_dynamic_indexing,
+    shader_uniform_buffer_array_non_uniform_indexing,
+    shader_sampled_image_array_non_uniform_indexing,
+    shader_storage_buffer_array_non_uniform_indexing,
+    shader_storage_image_array_non_uniform_indexing,
+    shader_input_attachment_array_non_uniform_indexing,
+    shader_uniform_texel_buffer_array_non_uniform_indexing,
+    shader_storage_texel_buffer_array_non_uniform_indexing,
+    descriptor_binding_uniform_buffer_update_after_bind,
+    descriptor_binding_sampled_image_update_after_bind,
+    descriptor_binding_storage_image_update_after_bind,
+    descriptor_binding_storage_buffer_update_after_bind,
+    descriptor_binding_uniform_texel_buffer_update_after_bind,
+    descriptor_binding_storage_texel_buffer_update_after_bind,
+    descriptor_binding_update_unused_while_pending,
+    descriptor_binding_partially_bound,
+    descriptor_binding_variable_descriptor_count,
+    runtime_descriptor_array,
+    sampler_filter_minmax,
+    scalar_block_layout,
+    imageless_framebuffer,
+    uniform_buffer_standard_layout,
+    shader_subgroup_extended_types,
+    separate_depth_stencil_layouts,
+    host_query_reset,
+    timeline_semaphore,
+    buffer_device_address,
+    buffer_device_address_capture_replay,
+    buffer_device_address_multi_device,
+    vulkan_memory_model,
+    vulkan_memory_model_device_scope,
+    vulkan_memory_model_availability_visibility_chains,
+    shader_output_viewport_index,
+    shader_output_layer,
+    subgroup_broadcast_dynamic_id,
+]);
+
+feature_table!(vulkan13_features, set_vulkan13_feature, vk::PhysicalDeviceVulkan13Features<'_>, [
+    robust_image_access,
+    inline_uniform_block,
+    descriptor_binding_inline_uniform_block_update_after_bind,
+    pipeline_creation_cache_control,
+    private_data,
+    shader_demote_to_helper_invocation,
+    shader_terminate_invocation,
+    subgroup_size_control,
+    compute_full_subgroups,
+    synchronization2,
+    texture_compression_astc_hdr,
+    shader_zero_initialize_workgroup_memory,
+    dynamic_rendering,
+    shader_integer_dot_product,
+    maintenance4,
+]);
+
+// Every feature struct we know about, for querying a device and for enabling features at
+// device creation. The 1.1 struct only exists from 1.2 on, so below 1.2 just the core features
+// are used and everything else reads as unsupported.
+#[derive(Clone, Copy, Default)]
+pub struct DeviceFeatures {
+    pub core: vk::PhysicalDeviceFeatures,
+    pub vulkan11: vk::PhysicalDeviceVulkan11Features<'static>,
+    pub vulkan12: vk::PhysicalDeviceVulkan12Features<'static>,
+    pub vulkan13: vk::PhysicalDeviceVulkan13Features<'static>,
+}
+
+impl DeviceFeatures {
+    // api_version should already be capped by both the instance and the device.
+    pub unsafe fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice, api_version: u32) -> Self {
+        let mut features = DeviceFeatures::default();
+        if api_version < vk::make_api_version(0, 1, 2, 0) {
+            features.core = instance.get_physical_device_features(physical_device);
+            return features;
+        }
+
+        let mut features2 = features.chain(api_version);
+        instance.get_physical_device_features2(physical_device, &mut features2);
+        features.core = features2.features;
+        features.unchain();
+        features
+    }
+
+    // Links the structs for vkGetPhysicalDeviceFeatures2 or vkCreateDevice. The result points
+    // into self, so self must not move until it has been used, and unchain() should follow.
+    pub fn chain(&mut self, api_version: u32) -> vk::PhysicalDeviceFeatures2<'static> {
+        let mut features2 = vk::PhysicalDeviceFeatures2 {
+            features: self.core,
+            ..Default::default()
+        };
+        if api_version >= vk::make_api_version(0, 1, 2, 0) {
+            features2.p_next = &mut self.vulkan11 as *mut _ as *mut c_void;
+            self.vulkan11.p_next = &mut self.vulkan12 as *mut _ as *mut c_void;
+        }
+        if api_version >= vk::make_api_version(0, 1, 3, 0) {
+            self.vulkan12.p_next = &mut self.vulkan13 as *mut _ as *mut c_void;
+        }
+        features2
+    }
+
+    pub fn unchain(&mut self) {
+        self.vulkan11.p_next = std::ptr::null_mut();
+        self.vulkan12.p_next = std::ptr::null_mut();
+        self.vulkan13.p_next = std::ptr::null_mut();
+    }
+
+    pub fn list(&self) -> Vec<(&'static str, bool)> {
+        let mut list = core_features(&self.core);
+        list.extend(vulkan11_features(&self.vulkan11));
+        list.extend(vulkan12_features(&self.vulkan12));
+        list.extend(vulkan13_features(&self.vulkan13));
+        list
+    }
+
+    // None if the name isn't a feature at all. Names are the ash field names, which are unique
+    // across the structs.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        self.list().into_iter().find(|&(n, _)| n == name).map(|(_, on)| on)
+    }
+
+    // Returns false if the name isn't a feature.
+    pub fn set(&mut self, name: &str, enable: bool) -> bool {
+        set_core_feature(&mut self.core, name, enable)
+            || set_vulkan11_feature(&mut self.vulkan11, name, enable)
+            || set_vulkan12_feature(&mut self.vulkan12, name, enable)
+            || set_vulkan13_feature(&mut self.vulkan13, name, enable)
+    }
+}
@@ -0,0 +1,339 @@
+use crate::device::*;
+use crate::loaders::*;
+use crate::memory::*;
+use crate::registry::*;
+
+use anyhow::{Context, Result};
+use ash::vk;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::rc::Rc;
+
+
+// Owned Vulkan handles. Each handle type knows how to destroy itself given the object it was
+// created from, so a struct made of Owned fields needs no Drop of its own, and a constructor
+// that fails half way cleans up whatever it had already created just by returning. Device
+// objects go through the device's deletion queue, since the GPU may still be using them. In
+// debug builds they're all in the registry until they're actually destroyed.
+
+pub trait Destroy<P>: vk::Handle + Copy {
+    unsafe fn destroy(self, parent: &P);
+
+    // What dropping an Owned does. Destroys straight away unless the handle says otherwise.
+    unsafe fn retire(self, parent: &P) {
+        self.destroy(parent);
+    }
+}
+
+macro_rules! destroy_with_device {
+    ($($ty:ident => $fn:ident),* $(,)?) => {
+        $(impl Destroy<Device> for vk::$ty {
+            unsafe fn destroy(self, device: &Device) {
+                unregister_object(device, self);
+                device.device.$fn(self, device.allocation_callbacks());
+            }
+
+            unsafe fn retire(self, device: &Device) {
+                device.defer_destroy(self);
+            }
+        })*
+    };
+}
+
+destroy_with_device!(
+    Semaphore => destroy_semaphore,
+    Fence => destroy_fence,
+    Buffer => destroy_buffer,
+    Image => destroy_image,
+    ImageView => destroy_image_view,
+    Framebuffer => destroy_framebuffer,
+    RenderPass => destroy_render_pass,
+    PipelineLayout => destroy_pipeline_layout,
+    Pipeline => destroy_pipeline,
+    PipelineCache => destroy_pipeline_cache,
+    ShaderModule => destroy_shader_module,
+    CommandPool => destroy_command_pool,
+    Sampler => destroy_sampler,
+    DescriptorSetLayout => destroy_descriptor_set_layout,
+    DescriptorPool => destroy_descriptor_pool,
+    QueryPool => destroy_query_pool,
+);
+
+// Not retired through the queue here, since the surface has to outlive it. See PerSwapchain.
+impl Destroy<Device> for vk::SwapchainKHR {
+    unsafe fn destroy(self, device: &Device) {
+        unregister_object(device, self);
+        device.swapchain_loader.destroy_swapchain(self, device.allocation_callbacks());
+    }
+}
+
+impl Destroy<Loaders> for vk::SurfaceKHR {
+    unsafe fn destroy(self, loaders: &Loaders) {
+        unregister_object(loaders, self);
+        loaders.surface.destroy_surface(self, loaders.allocation_callbacks());
+    }
+}
+
+impl Destroy<Loaders> for vk::DebugUtilsMessengerEXT {
+    unsafe fn destroy(self, loaders: &Loaders) {
+        unregister_object(loaders, self);
+        if let Some(debug_utils) = &loaders.debug_utils {
+            debug_utils.destroy_debug_utils_messenger(self, loaders.allocation_callbacks());
+        }
+    }
+}
+
+// Keeps its parent alive, so the parent can never be destroyed first.
+pub struct Owned<T: Destroy<P>, P = Device> {
+    pub parent: Rc<P>,
+    handle: T,
+}
+
+impl<T: Destroy<P>, P: Parent> Owned<T, P> {
+    // Takes ownership of a handle created from parent.
+    pub fn new(parent: Rc<P>, handle: T) -> Self {
+        register_object(&*parent, handle);
+        Owned { parent, handle }
+    }
+
+    pub fn handle(&self) -> T {
+        self.handle
+    }
+
+    // Gives the handle back without destroying it. It stays in the registry, so it's reported
+    // if nothing destroys it through Destroy.
+    pub fn into_raw(self) -> T {
+        // Skips our Drop, but the parent still has to be let go of.
+        let this = ManuallyDrop::new(self);
+        drop(unsafe { std::ptr::read(&this.parent) });
+        this.handle
+    }
+}
+
+impl<T: Destroy<Device>> Owned<T, Device> {
+    pub fn named(self, name: &str) -> Self {
+        self.parent.set_name(self.handle, name);
+        self
+    }
+}
+
+impl<T: Destroy<P>, P> Deref for Owned<T, P> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.handle
+    }
+}
+
+impl<T: Destroy<P>, P> Drop for Owned<T, P> {
+    fn drop(&mut self) {
+        if self.handle.as_raw() != 0 {
+            unsafe { self.handle.retire(&self.parent) };
+        }
+    }
+}
+
+// An allocator allocation that goes back to the allocator once the GPU is done with it.
+pub struct OwnedAllocation {
+    pub device: Rc<Device>,
+    allocation: Option<Allocation>,
+}
+
+impl OwnedAllocation {
+    pub fn new(device: Rc<Device>, allocation: Allocation) -> Self {
+        OwnedAllocation {
+            device,
+            allocation: Some(allocation),
+        }
+    }
+}
+
+impl Deref for OwnedAllocation {
+    type Target = Allocation;
+
+    fn deref(&self) -> &Allocation {
+        self.allocation.as_ref().unwrap()
+    }
+}
+
+impl Drop for OwnedAllocation {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            self.device.defer(move |device| unsafe { device.free(allocation) });
+        }
+    }
+}
+
+// Field order matters in these: the handle is retired before its memory, so it's destroyed first.
+
+pub struct Buffer {
+    pub buffer: Owned<vk::Buffer>,
+    pub memory: OwnedAllocation,
+    pub size: vk::DeviceSize,
+}
+
+impl Buffer {
+    pub unsafe fn new(
+        device: &Rc<Device>,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        memory_usage: MemoryUsage,
+        name: &str,
+    ) -> Result<Buffer> {
+        Self::with_info(
+            device,
+            &vk::BufferCreateInfo::default()
+                .size(size)
+                .usage(usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            memory_usage,
+            name,
+        )
+    }
+
+    pub unsafe fn with_info(
+        device: &Rc<Device>,
+        info: &vk::BufferCreateInfo,
+        memory_usage: MemoryUsage,
+        name: &str,
+    ) -> Result<Buffer> {
+        let buffer = device
+            .device
+            .create_buffer(info, device.allocation_callbacks())
+            .with_context(|| format!("Could not create {}", name))?;
+        let buffer = Owned::new(device.clone(), buffer).named(name);
+        let memory = device
+            .allocate_for_buffer(*buffer, memory_usage)
+            .with_context(|| format!("Could not allocate memory for {}", name))?;
+        Ok(Buffer {
+            buffer,
+            memory: OwnedAllocation::new(device.clone(), memory),
+            size: info.size,
+        })
+    }
+
+    pub fn handle(&self) -> vk::Buffer {
+        self.buffer.handle()
+    }
+
+    // Only for host visible memory.
+    pub unsafe fn write(&self, offset: vk::DeviceSize, bytes: &[u8]) -> Result<()> {
+        self.memory.device.write_memory(&self.memory, offset, bytes)
+    }
+
+    pub unsafe fn read(&self, offset: vk::DeviceSize, bytes: &mut [u8]) -> Result<()> {
+        self.memory.device.read_memory(&self.memory, offset, bytes)
+    }
+}
+
+pub struct Image {
+    pub image: Owned<vk::Image>,
+    pub memory: OwnedAllocation,
+    pub format: vk::Format,
+    pub extent: vk::Extent3D,
+}
+
+impl Image {
+    pub unsafe fn new(
+        device: &Rc<Device>,
+        info: &vk::ImageCreateInfo,
+        memory_usage: MemoryUsage,
+        name: &str,
+    ) -> Result<Image> {
+        let image = device
+            .device
+            .create_image(info, device.allocation_callbacks())
+            .with_context(|| format!("Could not create {}", name))?;
+        let image = Owned::new(device.clone(), image).named(name);
+        let linear = info.tiling == vk::ImageTiling::LINEAR;
+        let memory = device
+            .allocate_for_image(*image, memory_usage, linear)
+            .with_context(|| format!("Could not allocate memory for {}", name))?;
+        Ok(Image {
+            image,
+            memory: OwnedAllocation::new(device.clone(), memory),
+            format: info.format,
+            extent: info.extent,
+        })
+    }
+
+    pub fn handle(&self) -> vk::Image {
+        self.image.handle()
+    }
+}
+
+pub struct ImageView {
+    pub view: Owned<vk::ImageView>,
+}
+
+impl ImageView {
+    // A single mip, single layer 2D view, which is all the app needs so far.
+    pub unsafe fn new_2d(
+        device: &Rc<Device>,
+        image: vk::Image,
+        format: vk::Format,
+        aspect: vk::ImageAspectFlags,
+        name: &str,
+    ) -> Result<ImageView> {
+        let info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .components(vk::ComponentMapping::default())
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(aspect)
+                    .base_mip_level(0)
+                    .level_count(1)
+                    .base_array_layer(0)
+                    .layer_count(1),
+            );
+        let view = device
+            .device
+            .create_image_view(&info, device.allocation_callbacks())
+            .with_context(|| format!("Could not create {}", name))?;
+        Ok(ImageView {
+            view: Owned::new(device.clone(), view).named(name),
+        })
+    }
+
+    pub fn handle(&self) -> vk::ImageView {
+        self.view.handle()
+    }
+}
+
+pub struct Framebuffer {
+    pub framebuffer: Owned<vk::Framebuffer>,
+    pub extent: vk::Extent2D,
+}
+
+impl Framebuffer {
+    pub unsafe fn new(
+        device: &Rc<Device>,
+        render_pass: vk::RenderPass,
+        attachments: &[vk::ImageView],
+        extent: vk::Extent2D,
+        name: &str,
+    ) -> Result<Framebuffer> {
+        let framebuffer = device
+            .device
+            .create_framebuffer(
+                &vk::FramebufferCreateInfo::default()
+                    .render_pass(render_pass)
+                    .attachments(attachments)
+                    .width(extent.width)
+                    .height(extent.height)
+                    .layers(1),
+                device.allocation_callbacks(),
+            )
+            .with_context(|| format!("Could not create {}", name))?;
+        Ok(Framebuffer {
+            framebuffer: Owned::new(device.clone(), framebuffer).named(name),
+            extent,
+        })
+    }
+
+    pub fn handle(&self) -> vk::Framebuffer {
+        self.framebuffer.handle()
+    }
+}
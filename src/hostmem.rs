@@ -0,0 +1,229 @@
+use ash::vk;
+use std::alloc::{self, Layout};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+// A host allocator for the driver that counts what it hands out, split by
+// VkSystemAllocationScope. Lives in a `Box` on `Loaders` so the callbacks can hold a stable
+// pointer to it, and is passed to every create and destroy call so nothing goes untracked.
+
+const SCOPE_NAMES: [&str; 5] = ["command", "object", "cache", "device", "instance"];
+
+#[derive(Default)]
+pub struct ScopeCounters {
+    pub live_bytes: AtomicUsize,
+    pub live_count: AtomicUsize,
+    pub peak_bytes: AtomicUsize,
+    pub total_count: AtomicUsize,
+    // Memory the driver got for itself and only told us about.
+    pub internal_bytes: AtomicUsize,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScopeStats {
+    pub scope: &'static str,
+    pub live_bytes: usize,
+    pub live_count: usize,
+    pub peak_bytes: usize,
+    pub total_count: usize,
+    pub internal_bytes: usize,
+}
+
+pub struct HostMemoryTracker {
+    pub scopes: [ScopeCounters; 5],
+    pub callbacks: vk::AllocationCallbacks<'static>,
+}
+
+// Stored just before every pointer we return, so free and realloc know what they're releasing.
+#[derive(Clone, Copy)]
+struct Header {
+    size: usize,
+    align: usize,
+    offset: usize,
+    scope: usize,
+}
+
+impl HostMemoryTracker {
+    pub fn new() -> Box<HostMemoryTracker> {
+        let mut tracker = Box::new(HostMemoryTracker {
+            scopes: Default::default(),
+            callbacks: vk::AllocationCallbacks::default(),
+        });
+        let user_data = &*tracker as *const HostMemoryTracker as *mut c_void;
+        tracker.callbacks = vk::AllocationCallbacks {
+            p_user_data: user_data,
+            pfn_allocation: Some(allocation_callback),
+            pfn_reallocation: Some(reallocation_callback),
+            pfn_free: Some(free_callback),
+            pfn_internal_allocation: Some(internal_allocation_callback),
+            pfn_internal_free: Some(internal_free_callback),
+            ..Default::default()
+        };
+        tracker
+    }
+
+    pub fn stats(&self) -> Vec<ScopeStats> {
+        self.scopes
+            .iter()
+            .zip(SCOPE_NAMES)
+            .map(|(c, scope)| ScopeStats {
+                scope,
+                live_bytes: c.live_bytes.load(Ordering::Relaxed),
+                live_count: c.live_count.load(Ordering::Relaxed),
+                peak_bytes: c.peak_bytes.load(Ordering::Relaxed),
+                total_count: c.total_count.load(Ordering::Relaxed),
+                internal_bytes: c.internal_bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.scopes.iter().map(|c| c.live_bytes.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn live_count(&self) -> usize {
+        self.scopes.iter().map(|c| c.live_count.load(Ordering::Relaxed)).sum()
+    }
+
+    // Logs a line per scope that was ever used, and a warning if anything is still outstanding.
+    pub fn report(&self) {
+        for s in self.stats().iter().filter(|s| s.total_count > 0 || s.internal_bytes > 0) {
+            log::info!(
+                "Host memory, {} scope: {} allocations, peak {} bytes, {} bytes in {} allocations outstanding, {} internal bytes",
+                s.scope,
+                s.total_count,
+                s.peak_bytes,
+                s.live_bytes,
+                s.live_count,
+                s.internal_bytes
+            );
+        }
+        if self.live_count() > 0 {
+            log::warn!(
+                "Driver host memory still allocated: {} bytes in {} allocations",
+                self.live_bytes(),
+                self.live_count()
+            );
+        }
+    }
+
+    fn counters(&self, scope: usize) -> &ScopeCounters {
+        &self.scopes[scope.min(self.scopes.len() - 1)]
+    }
+
+    fn record_alloc(&self, scope: usize, size: usize) {
+        let c = self.counters(scope);
+        let live = c.live_bytes.fetch_add(size, Ordering::Relaxed) + size;
+        c.live_count.fetch_add(1, Ordering::Relaxed);
+        c.total_count.fetch_add(1, Ordering::Relaxed);
+        c.peak_bytes.fetch_max(live, Ordering::Relaxed);
+    }
+
+    fn record_free(&self, scope: usize, size: usize) {
+        let c = self.counters(scope);
+        c.live_bytes.fetch_sub(size, Ordering::Relaxed);
+        c.live_count.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    unsafe fn allocate(&self, size: usize, alignment: usize, scope: usize) -> *mut c_void {
+        if size == 0 {
+            return std::ptr::null_mut();
+        }
+        let align = alignment.max(std::mem::align_of::<Header>());
+        // Keeps the header aligned too, since align is a multiple of the header's alignment.
+        let offset = (std::mem::size_of::<Header>() + align - 1) / align * align;
+        let layout = match Layout::from_size_align(offset + size, align) {
+            Ok(layout) => layout,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let base = alloc::alloc(layout);
+        if base.is_null() {
+            return std::ptr::null_mut();
+        }
+        let ptr = base.add(offset);
+        (ptr as *mut Header).sub(1).write(Header {
+            size,
+            align,
+            offset,
+            scope,
+        });
+        self.record_alloc(scope, size);
+        ptr as *mut c_void
+    }
+
+    unsafe fn free(&self, ptr: *mut c_void) {
+        if ptr.is_null() {
+            return;
+        }
+        let header = (ptr as *const Header).sub(1).read();
+        self.record_free(header.scope, header.size);
+        let base = (ptr as *mut u8).sub(header.offset);
+        alloc::dealloc(base, Layout::from_size_align_unchecked(header.offset + header.size, header.align));
+    }
+}
+
+fn scope_index(scope: vk::SystemAllocationScope) -> usize {
+    scope.as_raw().max(0) as usize
+}
+
+unsafe extern "system" fn allocation_callback(
+    user_data: *mut c_void,
+    size: usize,
+    alignment: usize,
+    scope: vk::SystemAllocationScope,
+) -> *mut c_void {
+    let tracker = &*(user_data as *const HostMemoryTracker);
+    tracker.allocate(size, alignment, scope_index(scope))
+}
+
+unsafe extern "system" fn reallocation_callback(
+    user_data: *mut c_void,
+    original: *mut c_void,
+    size: usize,
+    alignment: usize,
+    scope: vk::SystemAllocationScope,
+) -> *mut c_void {
+    let tracker = &*(user_data as *const HostMemoryTracker);
+    if original.is_null() {
+        return tracker.allocate(size, alignment, scope_index(scope));
+    }
+    if size == 0 {
+        tracker.free(original);
+        return std::ptr::null_mut();
+    }
+
+    // The spec wants the original left alone if the new allocation fails.
+    let new = tracker.allocate(size, alignment, scope_index(scope));
+    if !new.is_null() {
+        let old_size = (original as *const Header).sub(1).read().size;
+        std::ptr::copy_nonoverlapping(original as *const u8, new as *mut u8, old_size.min(size));
+        tracker.free(original);
+    }
+    new
+}
+
+unsafe extern "system" fn free_callback(user_data: *mut c_void, memory: *mut c_void) {
+    let tracker = &*(user_data as *const HostMemoryTracker);
+    tracker.free(memory);
+}
+
+unsafe extern "system" fn internal_allocation_callback(
+    user_data: *mut c_void,
+    size: usize,
+    _kind: vk::InternalAllocationType,
+    scope: vk::SystemAllocationScope,
+) {
+    let tracker = &*(user_data as *const HostMemoryTracker);
+    tracker.counters(scope_index(scope)).internal_bytes.fetch_add(size, Ordering::Relaxed);
+}
+
+unsafe extern "system" fn internal_free_callback(
+    user_data: *mut c_void,
+    size: usize,
+    _kind: vk::InternalAllocationType,
+    scope: vk::SystemAllocationScope,
+) {
+    let tracker = &*(user_data as *const HostMemoryTracker);
+    tracker.counters(scope_index(scope)).internal_bytes.fetch_sub(size, Ordering::Relaxed);
+}
@@ -1,23 +1,65 @@
 
 use crate::util::*;
 use crate::config::*;
+use crate::debugmsg::*;
+use crate::hostmem::*;
+use crate::registry::*;
 use anyhow::{Context, Result};
-use ash::extensions::ext::DebugUtils;
+use ash::extensions::ext::{DebugUtils, HeadlessSurface};
 use winit::window::Window;
 
 use ash::vk;
 use std::default::Default;
+use std::collections::BTreeSet;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::fmt;
+use std::sync::Arc;
 
 pub struct Loaders {
     pub entry: ash::Entry,
     pub instance: ash::Instance,
     pub surface: ash::extensions::khr::Surface,
+    pub headless_surface: Option<HeadlessSurface>,
+    pub debug_utils: Option<DebugUtils>,
+    pub debug_messenger: vk::DebugUtilsMessengerEXT,
+    pub debug_counts: Arc<DebugMessageCounts>,
+    pub headless: bool,
+    pub config: InstanceConfig,
+    pub enabled_layers: Vec<CString>,
+    pub enabled_extensions: Vec<CString>,
+    pub api_version: u32,
+    pub validation_features: Vec<vk::ValidationFeatureEnableEXT>,
+    pub host_memory: Option<Box<HostMemoryTracker>>,
 }
 
 impl Loaders {
-    pub unsafe fn new(window: &Window) -> Result<Loaders> {
+    pub unsafe fn new(window: &Window, config: InstanceConfig) -> Result<Loaders> {
+        let surface_extensions = ash_window::enumerate_required_extensions(window)
+            .context("Could not get the surface extensions for this window")?
+            .iter()
+            .map(|s| Requested::required(&CStr::from_ptr(*s).to_string_lossy()))
+            .collect();
+
+        Self::create(surface_extensions, false, config)
+    }
+
+    // No window system involved at all, so this works on a display-less CI box. If the driver
+    // has VK_EXT_headless_surface (lavapipe does) we also get surfaces and swapchains that
+    // never reach a screen; otherwise none of the surface functions are usable.
+    pub unsafe fn new_headless(config: InstanceConfig) -> Result<Loaders> {
+        let surface_extensions = vec![
+            Requested::optional(&vk::KhrSurfaceFn::name().to_string_lossy()),
+            Requested::optional(&HeadlessSurface::name().to_string_lossy()),
+        ];
+        Self::create(surface_extensions, true, config)
+    }
+
+    unsafe fn create(
+        surface_extensions: Vec<Requested>,
+        headless: bool,
+        config: InstanceConfig,
+    ) -> Result<Loaders> {
         let entry = ash::Entry::load()?;
         let version = match entry.try_enumerate_instance_version() {
             Ok(Some(version)) => version,
@@ -26,80 +68,127 @@ impl Loaders {
                 return Err(e).context("Could not get Vulkan version.");
             }
         };
-        println!(
-            "{} - Vulkan Instance {}.{}.{}",
-            "Vulkan Tutorial (Rust)",
-            vk::api_version_major(version),
-            vk::api_version_minor(version),
-            vk::api_version_patch(version)
-        );
 
-        if let Ok(ext_props) = entry.enumerate_instance_extension_properties(None) {
-            for prop in ext_props.iter().enumerate() {
-                println!("base extension prop {} : {:?}", prop.0, prop.1);
-            }
-        } else {
-            println!("No extension props");
-        }
+        // A 1.0 loader rejects any apiVersion above 1.0, so never ask for more than it has.
+        let api_version = api_version_min(config.api_version, version);
+        log::info!(
+            "Vulkan loader {}, requested {}, using {}",
+            version_string(version),
+            version_string(config.api_version),
+            version_string(api_version)
+        );
 
-        if let Ok(layer_props) = entry.enumerate_instance_layer_properties() {
-            for prop in layer_props.iter().enumerate() {
-                println!("layer prop {} : {:?}", prop.0, prop.1);
-                if let Some(prop) = safer_cstr(&prop.1.layer_name) {
-                    if let Ok(ext_props) = entry.enumerate_instance_extension_properties(Some(prop))
-                    {
-                        for prop in ext_props.iter().enumerate() {
-                            println!("base extension prop {} : {:?}", prop.0, prop.1);
-                        }
-                    } else {
-                        println!("No extension props");
-                    }
-                }
-            }
-        } else {
-            println!("No layer props");
-        }
+        let available_layers: BTreeSet<CString> = entry
+            .enumerate_instance_layer_properties()
+            .context("Could not enumerate instance layers")?
+            .iter()
+            .filter_map(|props| safer_cstr(&props.layer_name).map(CString::from))
+            .collect();
+        log::debug!("Available instance layers: {:?}", available_layers);
 
-        let app_name = &CString::new("Triangle 1")?;
+        let app_name = &CString::new(config.app_name.as_str())?;
+        let engine_name = &CString::new(config.engine_name.as_str())?;
         let app_info = vk::ApplicationInfo::default()
             .application_name(app_name)
             .application_version(0)
-            .engine_name(app_name)
+            .engine_name(engine_name)
             .engine_version(0)
-            .api_version(vk::make_api_version(0, 1, 3, 0));
+            .api_version(api_version);
+
+        let mut missing = MissingInstanceSupport::default();
 
-        // Extensions
+        // Layers first, because the layers we end up enabling also decide which extensions exist.
 
-        let mut instance_extensions = Vec::<CString>::new();
-        let mut layers = Vec::<CString>::new();
+        let mut requested_layers = Vec::<Requested>::new();
+        if config.validation {
+            requested_layers.push(Requested::optional("VK_LAYER_KHRONOS_validation"));
+        }
+        requested_layers.extend(config.extra_layers.iter().cloned());
+
+        let layers = filter_available("layer", &requested_layers, &available_layers, &mut missing.layers)?;
 
-        if let Ok(win_ex) = ash_window::enumerate_required_extensions(window) {
-            instance_extensions.extend(
-                win_ex
+        let mut available_extensions = BTreeSet::<CString>::new();
+        for layer in [None].into_iter().chain(layers.iter().map(|l| Some(l.as_c_str()))) {
+            let ext_props = entry
+                .enumerate_instance_extension_properties(layer)
+                .with_context(|| format!("Could not enumerate instance extensions for {:?}", layer))?;
+            available_extensions.extend(
+                ext_props
                     .iter()
-                    .map(|s| CString::from(unsafe { CStr::from_ptr(*s) })),
+                    .filter_map(|props| safer_cstr(&props.extension_name).map(CString::from)),
             );
         }
+        log::debug!("Available instance extensions: {:?}", available_extensions);
 
-        if VK_DEBUG_LAYER {
-            layers.push(CString::new("VK_LAYER_KHRONOS_validation").unwrap());
-            instance_extensions.push(CString::from(DebugUtils::name()));
+        let mut requested_extensions = surface_extensions;
+        if config.validation {
+            requested_extensions.push(Requested::optional(&DebugUtils::name().to_string_lossy()));
+        }
+        if config.validation && config.validation_features.any() {
+            requested_extensions.push(Requested::optional(
+                &vk::ExtValidationFeaturesFn::name().to_string_lossy(),
+            ));
         }
+        requested_extensions.extend(config.extra_extensions.iter().cloned());
 
-        println!("Instance Extensions: {:?}", instance_extensions);
+        let instance_extensions = filter_available(
+            "extension",
+            &requested_extensions,
+            &available_extensions,
+            &mut missing.extensions,
+        )?;
+
+        if !missing.is_empty() {
+            return Err(missing.into());
+        }
+
+        log::info!("Instance layers: {:?}", layers);
+        log::info!("Instance extensions: {:?}", instance_extensions);
+
+        let debug_utils_enabled = instance_extensions.iter().any(|e| e.as_c_str() == DebugUtils::name());
+
+        let validation_features = if instance_extensions
+            .iter()
+            .any(|e| e.as_c_str() == vk::ExtValidationFeaturesFn::name())
+        {
+            validation_feature_list(&config.validation_features)
+        } else {
+            if config.validation && config.validation_features.any() {
+                log::warn!("VK_EXT_validation_features is unavailable, using default validation only");
+            }
+            Vec::new()
+        };
+        let mut validation_features_info =
+            vk::ValidationFeaturesEXT::default().enabled_validation_features(&validation_features);
 
         let instance_extensions_raw: Vec<*const i8> =
             instance_extensions.iter().map(|c| c.as_ptr()).collect();
         let layers_raw: Vec<*const i8> = layers.iter().map(|c| c.as_ptr()).collect();
 
+        let debug_counts = Arc::new(DebugMessageCounts::default());
+        let mut instance_messenger_info = messenger_create_info(&debug_counts);
+
+        let mut instance_info = vk::InstanceCreateInfo::default()
+            .application_info(&app_info)
+            .enabled_layer_names(&layers_raw)
+            .enabled_extension_names(&instance_extensions_raw);
+        if debug_utils_enabled {
+            instance_info = instance_info.push_next(&mut instance_messenger_info);
+        }
+        if !validation_features.is_empty() {
+            log::info!("Validation features: {:?}", validation_features);
+            instance_info = instance_info.push_next(&mut validation_features_info);
+        }
+
+        let host_memory = if config.track_host_memory {
+            Some(HostMemoryTracker::new())
+        } else {
+            None
+        };
+        let allocation_callbacks = host_memory.as_ref().map(|t| &t.callbacks);
+
         let instance = entry
-            .create_instance(
-                &vk::InstanceCreateInfo::default()
-                    .application_info(&app_info)
-                    .enabled_layer_names(&layers_raw)
-                    .enabled_extension_names(&instance_extensions_raw),
-                None,
-            )
+            .create_instance(&instance_info, allocation_callbacks)
             .with_context(|| {
                 format!(
                     "Could not create Vulkan instance. Version {:#x} extensions {:?}, layers {:?}",
@@ -108,19 +197,158 @@ impl Loaders {
             })?;
 
         let surface = ash::extensions::khr::Surface::new(&entry, &instance);
+        let headless_surface = if instance_extensions.iter().any(|e| e.as_c_str() == HeadlessSurface::name()) {
+            Some(HeadlessSurface::new(&entry, &instance))
+        } else {
+            None
+        };
+
+        let (debug_utils, debug_messenger) = if debug_utils_enabled {
+            let debug_utils = DebugUtils::new(&entry, &instance);
+            match debug_utils.create_debug_utils_messenger(&messenger_create_info(&debug_counts), allocation_callbacks) {
+                Ok(messenger) => (Some(debug_utils), messenger),
+                Err(e) => {
+                    instance.destroy_instance(allocation_callbacks);
+                    return Err(e).context("Could not create debug utils messenger");
+                }
+            }
+        } else {
+            (None, vk::DebugUtilsMessengerEXT::null())
+        };
 
-        Ok(Loaders {
+        let loaders = Loaders {
             entry,
             instance,
             surface,
-        })
+            headless_surface,
+            debug_utils,
+            debug_messenger,
+            debug_counts,
+            headless,
+            config,
+            enabled_layers: layers,
+            enabled_extensions: instance_extensions,
+            api_version,
+            validation_features,
+            host_memory,
+        };
+        register_object(&loaders, loaders.debug_messenger);
+        Ok(loaders)
+    }
+
+    // Passed to every create and destroy call, so the tracker sees all driver host memory.
+    pub fn allocation_callbacks(&self) -> Option<&vk::AllocationCallbacks<'static>> {
+        self.host_memory.as_ref().map(|t| &t.callbacks)
+    }
+
+    pub fn supports_api(&self, major: u32, minor: u32) -> bool {
+        self.api_version >= vk::make_api_version(0, major, minor, 0)
+    }
+
+    pub fn has_layer(&self, name: &CStr) -> bool {
+        self.enabled_layers.iter().any(|l| l.as_c_str() == name)
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.enabled_extensions.iter().any(|e| e.as_c_str() == name)
+    }
+}
+
+// Everything that was required but isn't installed, so the error can name it all at once
+// rather than failing on the first missing piece.
+#[derive(Debug, Default)]
+pub struct MissingInstanceSupport {
+    pub layers: Vec<String>,
+    pub extensions: Vec<String>,
+}
+
+impl MissingInstanceSupport {
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty() && self.extensions.is_empty()
+    }
+}
+
+impl fmt::Display for MissingInstanceSupport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Required Vulkan instance support is missing.")?;
+        if !self.layers.is_empty() {
+            write!(f, " Layers: {}.", self.layers.join(", "))?;
+        }
+        if !self.extensions.is_empty() {
+            write!(f, " Extensions: {}.", self.extensions.join(", "))?;
+        }
+        Ok(())
     }
 }
 
+impl std::error::Error for MissingInstanceSupport {}
+
+fn validation_feature_list(features: &ValidationFeatures) -> Vec<vk::ValidationFeatureEnableEXT> {
+    let mut list = Vec::new();
+    if features.best_practices {
+        list.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+    }
+    if features.synchronization {
+        list.push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+    }
+    if features.gpu_assisted {
+        list.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+        list.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+    }
+    // The layer can't instrument shaders for both at once.
+    if features.debug_printf && features.gpu_assisted {
+        log::warn!("Debug printf can't be combined with GPU-assisted validation, leaving it off");
+    } else if features.debug_printf {
+        list.push(vk::ValidationFeatureEnableEXT::DEBUG_PRINTF);
+    }
+    list
+}
+
+fn filter_available(
+    kind: &str,
+    requested: &[Requested],
+    available: &BTreeSet<CString>,
+    missing: &mut Vec<String>,
+) -> Result<Vec<CString>> {
+    let mut enabled = Vec::<CString>::new();
+    for req in requested {
+        let name = CString::new(req.name.as_str())?;
+        if enabled.contains(&name) {
+            continue;
+        }
+        if available.contains(&name) {
+            enabled.push(name);
+        } else if req.required {
+            missing.push(req.name.clone());
+        } else {
+            log::warn!("Optional instance {} {} is not available, skipping it", kind, req.name);
+        }
+    }
+    Ok(enabled)
+}
+
 impl Drop for Loaders {
     fn drop(&mut self) {
         unsafe {
-            self.instance.destroy_instance(None);
+            if let Some(debug_utils) = &self.debug_utils {
+                unregister_object(self, self.debug_messenger);
+                debug_utils.destroy_debug_utils_messenger(self.debug_messenger, self.allocation_callbacks());
+            }
+            self.instance.destroy_instance(self.allocation_callbacks());
+        }
+        report_live_objects(self, "instance");
+
+        // Everything created from this instance is gone now, so anything left is a leak.
+        if let Some(host_memory) = &self.host_memory {
+            host_memory.report();
+        }
+
+        if self.debug_counts.errors() > 0 || self.debug_counts.warnings() > 0 {
+            log::warn!(
+                "Validation reported {} errors and {} warnings during this run",
+                self.debug_counts.errors(),
+                self.debug_counts.warnings()
+            );
         }
     }
 }
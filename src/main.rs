@@ -8,18 +8,34 @@
 
 mod util;
 mod config;
+mod debugmsg;
+mod hostmem;
+mod features;
+mod report;
 mod loaders;
 mod device;
+mod memory;
+mod handle;
+mod registry;
+mod barriers;
+mod upload;
+mod deletion;
+mod timeline;
+mod pipelinecache;
 mod perframe;
 mod swapsurface;
 mod renderer;
 mod window;
+mod selftest;
+mod exitcode;
 
+use crate::config::*;
 use crate::loaders::*;
 use crate::device::*;
 use crate::perframe::*;
 use crate::swapsurface::*;
 use crate::renderer::*;
+use crate::report::*;
 use crate::window::*;
 
 use anyhow::{Context, Result};
@@ -38,6 +54,26 @@ use rand::prelude::*;
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let has_flag = |flag: &str| args.iter().any(|arg| arg == flag);
+
+    if has_flag("--vkinfo") {
+        return unsafe { print_vkinfo(has_flag("--headless"), has_flag("--json")) };
+    }
+
+    if has_flag("--selftest") {
+        let result = unsafe { selftest::run() };
+        if let Some(unsupported) = result.as_ref().err().and_then(|e| e.downcast_ref::<selftest::Unsupported>()) {
+            eprintln!("Self test skipped: {}", unsupported);
+            std::process::exit(exitcode::SKIPPED_EXIT_CODE);
+        }
+        return result;
+    }
+
+    if has_flag("--headless") {
+        return unsafe { headless_check() };
+    }
+
     let event_loop = EventLoop::new();
 
     let mut app = unsafe { App::create(&event_loop)? };
@@ -58,28 +94,50 @@ fn main() -> Result<()> {
         match event {
             // Render a frame if our Vulkan app is not being destroyed.
             Event::MainEventsCleared if !destroying => unsafe {
+                let mut device_lost = false;
+                let mut failed = false;
                 for w in app.windows.values_mut() {
                     match app.renderer.render(w) {
                         Ok(_) => {}
                         Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
                             println!("Out of date");
-                            let dev = &app.renderer.device.device;
-                            let _ = dev.device_wait_idle();
-                            w.swap = PerSwapchain::new(
-                                app.renderer.device.clone(),
-                                &w.window,
-                                w.surface.clone(),
-                                Some(&app.renderer),
-                                Some(&w.swap),
-                            )
-                            .context("Recreating swapchain")
-                            .unwrap();
+                            if let Err(e) = w.recreate_swapchain(&app.renderer) {
+                                log::error!("Could not recreate the swapchain for {}: {:?}", w.label, e);
+                                failed = true;
+                            }
+                        }
+                        Err(vk::Result::ERROR_SURFACE_LOST_KHR) => {
+                            log::warn!("{} lost its surface, creating a new one", w.label);
+                            if let Err(e) = w.recreate_surface(&app.renderer) {
+                                log::error!("Could not recreate the surface for {}: {:?}", w.label, e);
+                                failed = true;
+                            }
+                        }
+                        // Everything made from the device is unusable, so there's no point
+                        // rendering the other windows this time round.
+                        Err(vk::Result::ERROR_DEVICE_LOST) => {
+                            device_lost = true;
+                            break;
                         }
                         Err(e) => {
-                            panic!("Unexpected Vulkan error {} while rendering", e);
+                            log::error!("Unexpected Vulkan error {} while rendering {}", e, w.label);
+                            failed = true;
                         }
                     }
                 }
+
+                if device_lost {
+                    let mut windows: Vec<&mut VulkanWindow> = app.windows.values_mut().collect();
+                    if let Err(e) = app.renderer.recover_device_lost(&mut windows) {
+                        log::error!("Could not recover from losing the device: {:?}", e);
+                        failed = true;
+                    }
+                }
+
+                if failed {
+                    destroying = true;
+                    *control_flow = ControlFlow::Exit;
+                }
             },
             // Destroy our Vulkan app.
             Event::WindowEvent {
@@ -99,7 +157,11 @@ fn main() -> Result<()> {
                     },
                 window_id,
             } => match key {
-                VirtualKeyCode::N => { app.add_window(el_window_target); },
+                VirtualKeyCode::N => {
+                    if let Err(e) = app.add_window(el_window_target) {
+                        log::error!("Could not open a new window: {:?}", e);
+                    }
+                },
                 VirtualKeyCode::Escape => { close_window(window_id, &mut destroying); },
                 _ => {}
             },
@@ -110,9 +172,75 @@ fn main() -> Result<()> {
 
 
 
+// Brings Vulkan up and back down without any window system, as a smoke test for CI machines.
+unsafe fn headless_check() -> Result<()> {
+    let loaders = Rc::new(
+        Loaders::new_headless(InstanceConfig::default().apply_env())
+            .context("Could not create headless Vulkan Loaders")?,
+    );
+    let device = Device::create_headless(loaders.clone(), DeviceConfig::default().apply_env())
+        .context("Could not create headless Vulkan Device")?;
+
+    let props = loaders.instance.get_physical_device_properties(device.physical_device);
+    println!(
+        "Headless device: {}",
+        util::safer_cstr(&props.device_name).map_or("<unnamed>".into(), |name| name.to_string_lossy())
+    );
+
+    for heap in device.memory_stats() {
+        let budget = match (heap.budget, heap.usage) {
+            (Some(budget), Some(usage)) => format!(", budget {} MiB, in use {} MiB", budget >> 20, usage >> 20),
+            _ => String::new(),
+        };
+        println!(
+            "Memory heap {}: {} MiB{}{}",
+            heap.heap,
+            heap.size >> 20,
+            if heap.device_local { " device local" } else { "" },
+            budget
+        );
+    }
+
+    Ok(())
+}
+
+// Capability report for bug reports. Without --headless an invisible window is created so the
+// surface formats and present modes can be included too.
+unsafe fn print_vkinfo(headless: bool, json: bool) -> Result<()> {
+    let report = if headless {
+        let loaders = Loaders::new_headless(InstanceConfig::default().apply_env())
+            .context("Could not create headless Vulkan Loaders")?;
+        VkInfoReport::collect(&loaders, None)?
+    } else {
+        let event_loop = EventLoop::new();
+        let window = WindowBuilder::new()
+            .with_title("VK_RUSTY_TRIANGLE")
+            .with_visible(false)
+            .build(&event_loop)
+            .context("Could not create window.")?;
+
+        let loaders = Rc::new(
+            Loaders::new(&window, InstanceConfig::default().apply_env())
+                .context("Could not create Vulkan Loaders")?,
+        );
+        let surface = Surface::for_window(loaders.clone(), &window)?;
+        VkInfoReport::collect(&loaders, Some(surface.surface))?
+    };
+
+    if json {
+        println!("{}", report.to_json()?);
+    } else {
+        print!("{}", report.summary());
+    }
+
+    Ok(())
+}
+
+
 struct App {
     renderer: Renderer,
     windows: HashMap<winit::window::WindowId, VulkanWindow>,
+    next_window_number: usize,
 }
 
 
@@ -132,20 +260,20 @@ impl App {
             .build(&event_loop)
             .context("Could not create window.")?;
 
-        let loaders = Rc::new(Loaders::new(&window).context("Could not create Vulkan Loaders")?);
+        let loaders = Rc::new(
+            Loaders::new(&window, InstanceConfig::default().apply_env())
+                .context("Could not create Vulkan Loaders")?,
+        );
 
-        let surface = Rc::new(Surface {
-            loaders: loaders.clone(),
-            surface: ash_window::create_surface(&loaders.entry, &loaders.instance, &window, None)
-                .context("Could not create surface from window handle")?,
-        });
+        let surface = Rc::new(Surface::for_window(loaders.clone(), &window)?);
 
         let device = Rc::new(
-            Device::create(loaders.clone(), surface.surface)
+            Device::create(loaders.clone(), surface.surface, DeviceConfig::default().apply_env())
                 .context("Could not create Vulkan Device")?,
         );
 
-        let mut swap = PerSwapchain::new(device.clone(), &window, surface.clone(), None, None)
+        let label = "window 0".to_string();
+        let mut swap = PerSwapchain::new(device.clone(), window_extent(&window), surface.clone(), None, None, &label)
             .context("Could not create initial swapchain")?;
 
         let renderer = Renderer::new(device.clone(), &swap).context("Could not create Renderer")?;
@@ -153,34 +281,21 @@ impl App {
         swap.create_framebuffers(&renderer)
             .context("Could not create framebuffers")?;
 
-        let per_frame: Vec<PerFrame> = (0..4)
-            .map(|_| PerFrame::new(device.clone()))
-            .collect::<VkResult<Vec<PerFrame>>>()?;
-
-        let v_win = VulkanWindow {
-            window,
-            surface,
-            device: device.clone(),
-            swap,
-            per_frame,
-
-            frame_count: 0,
-            count_start_time: std::time::Instant::now(),
-            count_start_frame: 0,
-
-            anim_start_time: std::time::Instant::now(),
-            shape_rotate_speed: thread_rng().gen_range(-1.5..1.5) as f32,
-            color_rotate_speed: thread_rng().gen_range(-1.5..1.5) as f32,
-            background_color: [ 1.0, 1.0, 1.0, 0.0 ]
-        };
+        let window_id = window.id();
+        let mut v_win = VulkanWindow::new(label, Some(window), vk::Extent2D::default(), surface, swap)?;
+        v_win.shape_rotate_speed = thread_rng().gen_range(-1.5..1.5) as f32;
+        v_win.color_rotate_speed = thread_rng().gen_range(-1.5..1.5) as f32;
+        v_win.injected_fault = fault_injection_from_env();
 
         let mut windows = HashMap::new();
-        windows.insert(v_win.window.id(), v_win);
+        windows.insert(window_id, v_win);
 
-        Ok(Self { renderer, windows })
+        Ok(Self { renderer, windows, next_window_number: 1 })
     }
 
-    fn add_window(&mut self, event_loop: &EventLoopWindowTarget<()>) {
+    // Fails cleanly, leaving the other windows running, if the device can't present to the new
+    // window's surface.
+    fn add_window(&mut self, event_loop: &EventLoopWindowTarget<()>) -> Result<()> {
         unsafe {
             let monitor = event_loop.primary_monitor().or_else(|| event_loop.available_monitors().next()).unwrap();
             let monitor_size = monitor.size();
@@ -197,62 +312,37 @@ impl App {
                 .with_inner_size(size)
                 .with_position(pos)
                 .build(event_loop)
-                .context("Could not create window.")
-                .unwrap();
-
-            let loaders = &self.renderer.device.loaders;
-            let surface = Rc::new(Surface {
-                loaders: self.renderer.device.loaders.clone(),
-                surface: ash_window::create_surface(
-                    &loaders.entry,
-                    &loaders.instance,
-                    &window,
-                    None,
-                )
-                .context("Could not create surface from window handle")
-                .unwrap(),
-            });
+                .context("Could not create window.")?;
+
+            let surface = Rc::new(Surface::for_window(self.renderer.device.loaders.clone(), &window)?);
+
+            let label = format!("window {}", self.next_window_number);
+            self.next_window_number += 1;
 
             let swap = PerSwapchain::new(
                 self.renderer.device.clone(),
-                &window,
+                window_extent(&window),
                 surface.clone(),
                 Some(&self.renderer),
                 None,
+                &label,
             )
-            .context("Could not create additional swapchain")
-            .unwrap();
-
-            let per_frame: Vec<PerFrame> = (0..4)
-                .map(|_| PerFrame::new(self.renderer.device.clone()))
-                .collect::<VkResult<Vec<PerFrame>>>()
-                .context("Could not create per-frame queues")
-                .unwrap();
+            .context("Could not create additional swapchain")?;
 
             let rate = thread_rng().gen_range(0.0f32..3.0f32);
             let c = thread_rng().gen_range(0.1f32..0.9f32);
             let r = c + thread_rng().gen_range(-0.09f32..0.09f32);
             let g = c + thread_rng().gen_range(-0.09f32..0.09f32);
             let b = c + thread_rng().gen_range(-0.09f32..0.09f32);
-            let v_win = VulkanWindow {
-                window,
-                surface,
-                device: self.renderer.device.clone(),
-                swap,
-                per_frame,
-
-                frame_count: 0,
-                count_start_time: std::time::Instant::now(),
-                count_start_frame: 0,
-
-                anim_start_time: std::time::Instant::now(),
-                shape_rotate_speed: rate - 1.5f32,
-                color_rotate_speed: (rate + 1.5f32 % 3.0) - 1.5f32,
-                background_color: [ r, g, b, 0.0 ]
-            };
+            let window_id = window.id();
+            let mut v_win = VulkanWindow::new(label, Some(window), vk::Extent2D::default(), surface, swap)?;
+            v_win.shape_rotate_speed = rate - 1.5f32;
+            v_win.color_rotate_speed = (rate + 1.5f32 % 3.0) - 1.5f32;
+            v_win.background_color = [ r, g, b, 0.0 ];
 
-            self.windows.insert(v_win.window.id(), v_win);
+            self.windows.insert(window_id, v_win);
         }
+        Ok(())
     }
 }
 
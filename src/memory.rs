@@ -0,0 +1,574 @@
+use crate::device::*;
+use crate::registry::*;
+
+use anyhow::{bail, Context, Result};
+use ash::vk;
+use std::os::raw::c_void;
+
+
+// Device memory sub-allocator. vkAllocateMemory is slow and drivers only promise 4096 live
+// allocations, so memory is taken in large blocks and handed out in pieces. Lives in a RefCell
+// on Device; use it through Device::allocate and friends.
+
+const BLOCK_SIZE: vk::DeviceSize = 64 << 20;
+
+// What the memory is for, which decides the memory type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryUsage {
+    // Only the GPU touches it. Vertex buffers, textures, render targets.
+    GpuOnly,
+    // Written once by the CPU, read by the GPU. Staging buffers and per-frame constants.
+    Upload,
+    // Written by the GPU, read back by the CPU. Screenshots and query results.
+    Readback,
+}
+
+impl MemoryUsage {
+    fn required_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            MemoryUsage::GpuOnly => vk::MemoryPropertyFlags::empty(),
+            MemoryUsage::Upload | MemoryUsage::Readback => vk::MemoryPropertyFlags::HOST_VISIBLE,
+        }
+    }
+
+    // Higher is better. Anything with the required flags is usable, this just picks among them.
+    fn type_score(self, flags: vk::MemoryPropertyFlags) -> i32 {
+        let has = |f: vk::MemoryPropertyFlags| flags.contains(f) as i32;
+        match self {
+            MemoryUsage::GpuOnly => 4 * has(vk::MemoryPropertyFlags::DEVICE_LOCAL) - has(vk::MemoryPropertyFlags::HOST_VISIBLE),
+            MemoryUsage::Upload => 2 * has(vk::MemoryPropertyFlags::HOST_COHERENT) - has(vk::MemoryPropertyFlags::HOST_CACHED),
+            MemoryUsage::Readback => 2 * has(vk::MemoryPropertyFlags::HOST_CACHED) + has(vk::MemoryPropertyFlags::HOST_COHERENT),
+        }
+    }
+}
+
+// A piece of a block. Has to be handed back to Device::free, it isn't freed on drop.
+#[derive(Debug)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    pub memory_type: u32,
+    pub usage: MemoryUsage,
+    pub coherent: bool,
+    // Start of this allocation in the persistently mapped block, or null if not host visible.
+    pub mapped: *mut u8,
+    block: u64,
+}
+
+struct Block {
+    id: u64,
+    memory: vk::DeviceMemory,
+    memory_type: u32,
+    // Buffers and linear images go in separate blocks from optimal images, which avoids
+    // having to respect bufferImageGranularity between neighbours.
+    linear: bool,
+    // Made for one allocation too big for a normal block, and freed along with it.
+    dedicated: bool,
+    size: vk::DeviceSize,
+    mapped: *mut u8,
+    // (offset, size), sorted by offset and never adjacent.
+    free: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    allocation_count: usize,
+}
+
+impl Block {
+    fn try_allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        // A dedicated block only ever holds the allocation it was made for.
+        if self.dedicated && self.allocation_count > 0 {
+            return None;
+        }
+        for i in 0..self.free.len() {
+            let (start, len) = self.free[i];
+            let offset = align_up(start, alignment);
+            if offset + size > start + len {
+                continue;
+            }
+
+            // Split the free range into whatever is left on either side.
+            let before = (start, offset - start);
+            let after = (offset + size, start + len - offset - size);
+            self.free.remove(i);
+            for (j, range) in [before, after].into_iter().filter(|r| r.1 > 0).enumerate() {
+                self.free.insert(i + j, range);
+            }
+            self.allocation_count += 1;
+            return Some(offset);
+        }
+        None
+    }
+
+    fn release(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let i = self.free.partition_point(|&(o, _)| o < offset);
+        self.free.insert(i, (offset, size));
+        // Merge with the following range, then the preceding one.
+        if i + 1 < self.free.len() && self.free[i].0 + self.free[i].1 == self.free[i + 1].0 {
+            self.free[i].1 += self.free[i + 1].1;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == self.free[i].0 {
+            self.free[i - 1].1 += self.free[i].1;
+            self.free.remove(i);
+        }
+        self.allocation_count -= 1;
+    }
+
+    fn free_bytes(&self) -> vk::DeviceSize {
+        self.free.iter().map(|&(_, len)| len).sum()
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    let alignment = alignment.max(1);
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn align_down(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value / alignment.max(1) * alignment.max(1)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HeapStats {
+    pub heap: u32,
+    pub size: vk::DeviceSize,
+    pub device_local: bool,
+    // Our own blocks in this heap, and how much of them is handed out.
+    pub block_count: usize,
+    pub block_bytes: vk::DeviceSize,
+    pub allocation_count: usize,
+    pub allocated_bytes: vk::DeviceSize,
+    // From VK_EXT_memory_budget, covering the whole process and not just this allocator.
+    pub budget: Option<vk::DeviceSize>,
+    pub usage: Option<vk::DeviceSize>,
+}
+
+pub struct Allocator {
+    pub memory_properties: vk::PhysicalDeviceMemoryProperties,
+    pub non_coherent_atom_size: vk::DeviceSize,
+    blocks: Vec<Block>,
+    next_block_id: u64,
+}
+
+impl Allocator {
+    pub fn new(memory_properties: vk::PhysicalDeviceMemoryProperties, limits: &vk::PhysicalDeviceLimits) -> Self {
+        Allocator {
+            memory_properties,
+            non_coherent_atom_size: limits.non_coherent_atom_size,
+            blocks: Vec::new(),
+            next_block_id: 0,
+        }
+    }
+
+    pub fn memory_type_flags(&self, memory_type: u32) -> vk::MemoryPropertyFlags {
+        self.memory_properties.memory_types[memory_type as usize].property_flags
+    }
+
+    pub fn find_memory_type(&self, type_bits: u32, usage: MemoryUsage) -> Option<u32> {
+        (0..self.memory_properties.memory_type_count)
+            .filter(|&i| type_bits & (1 << i) != 0)
+            .filter(|&i| self.memory_type_flags(i).contains(usage.required_flags()))
+            // max_by_key keeps the last of equals, so reverse to prefer the lower index.
+            .rev()
+            .max_by_key(|&i| usage.type_score(self.memory_type_flags(i)))
+    }
+
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        requirements: vk::MemoryRequirements,
+        usage: MemoryUsage,
+        linear: bool,
+    ) -> Result<Allocation> {
+        let memory_type = self
+            .find_memory_type(requirements.memory_type_bits, usage)
+            .with_context(|| format!("No memory type suits {:?} with type bits {:#x}", usage, requirements.memory_type_bits))?;
+        let flags = self.memory_type_flags(memory_type);
+        let coherent = flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT);
+
+        // Non-coherent ranges are flushed in whole atoms, so keep allocations atom aligned or a
+        // flush could reach into a neighbour.
+        let mut alignment = requirements.alignment;
+        let mut size = requirements.size;
+        if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) && !coherent {
+            alignment = alignment.max(self.non_coherent_atom_size);
+            size = align_up(size, self.non_coherent_atom_size);
+        }
+
+        let existing = self
+            .blocks
+            .iter_mut()
+            .filter(|b| b.memory_type == memory_type && b.linear == linear)
+            .find_map(|b| b.try_allocate(size, alignment).map(|offset| (b.id, offset)));
+
+        let (block_id, offset) = match existing {
+            Some(found) => found,
+            None => {
+                // Anything bigger than a normal block gets a block of its own.
+                let block_size = self.block_size(memory_type);
+                let block = if size > block_size {
+                    self.create_block(device, memory_type, linear, size, true)?
+                } else {
+                    self.create_block(device, memory_type, linear, block_size, false)?
+                };
+                let offset = block.try_allocate(size, alignment).context("Fresh memory block is too small")?;
+                (block.id, offset)
+            }
+        };
+
+        let block = self.blocks.iter().find(|b| b.id == block_id).unwrap();
+        Ok(Allocation {
+            memory: block.memory,
+            offset,
+            size,
+            memory_type,
+            usage,
+            coherent,
+            mapped: if block.mapped.is_null() { block.mapped } else { block.mapped.add(offset as usize) },
+            block: block_id,
+        })
+    }
+
+    // A small heap, like a 256MB BAR window, shouldn't be filled by one block.
+    fn block_size(&self, memory_type: u32) -> vk::DeviceSize {
+        let heap = self.memory_properties.memory_types[memory_type as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap as usize].size;
+        BLOCK_SIZE.min(heap_size / 8).max(1 << 20)
+    }
+
+    unsafe fn create_block(
+        &mut self,
+        device: &Device,
+        memory_type: u32,
+        linear: bool,
+        size: vk::DeviceSize,
+        dedicated: bool,
+    ) -> Result<&mut Block> {
+        let heap = self.memory_properties.memory_types[memory_type as usize].heap_index;
+        if size > self.memory_properties.memory_heaps[heap as usize].size {
+            bail!("Allocation of {} bytes is bigger than memory heap {}", size, heap);
+        }
+
+        let memory = device
+            .device
+            .allocate_memory(
+                &vk::MemoryAllocateInfo::default()
+                    .allocation_size(size)
+                    .memory_type_index(memory_type),
+                device.allocation_callbacks(),
+            )
+            .with_context(|| format!("Could not allocate a {} byte block of memory type {}", size, memory_type))?;
+
+        let mapped = if self.memory_type_flags(memory_type).contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            match device.device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()) {
+                Ok(ptr) => ptr as *mut u8,
+                Err(e) => {
+                    device.device.free_memory(memory, device.allocation_callbacks());
+                    return Err(e).context("Could not map memory block");
+                }
+            }
+        } else {
+            std::ptr::null_mut()
+        };
+
+        let id = self.next_block_id;
+        self.next_block_id += 1;
+        register_object(device, memory);
+        device.set_name(memory, &format!("memory block {} (type {})", id, memory_type));
+        log::debug!("New memory block {}: {} bytes of type {} in heap {}", id, size, memory_type, heap);
+
+        self.blocks.push(Block {
+            id,
+            memory,
+            memory_type,
+            linear,
+            dedicated,
+            size,
+            mapped,
+            free: vec![(0, size)],
+            allocation_count: 0,
+        });
+        Ok(self.blocks.last_mut().unwrap())
+    }
+
+    pub unsafe fn free(&mut self, device: &Device, allocation: Allocation) {
+        let index = match self.blocks.iter().position(|b| b.id == allocation.block) {
+            Some(index) => index,
+            None => {
+                log::error!("Freeing an allocation from unknown memory block {}", allocation.block);
+                return;
+            }
+        };
+        let block = &mut self.blocks[index];
+        block.release(allocation.offset, allocation.size);
+
+        // Keep one empty block of each kind around so an allocate/free cycle doesn't thrash.
+        if block.allocation_count == 0 {
+            let (memory_type, linear) = (block.memory_type, block.linear);
+            let spare = self
+                .blocks
+                .iter()
+                .filter(|b| b.memory_type == memory_type && b.linear == linear && b.allocation_count == 0)
+                .count();
+            if spare > 1 || self.blocks[index].dedicated {
+                let block = self.blocks.remove(index);
+                Self::destroy_block(device, &block);
+            }
+        }
+    }
+
+    unsafe fn destroy_block(device: &Device, block: &Block) {
+        unregister_object(device, block.memory);
+        if !block.mapped.is_null() {
+            device.device.unmap_memory(block.memory);
+        }
+        device.device.free_memory(block.memory, device.allocation_callbacks());
+    }
+
+    // Frees every block. Only for Device::drop; anything still allocated is reported.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let live: usize = self.blocks.iter().map(|b| b.allocation_count).sum();
+        if live > 0 {
+            log::warn!("Destroying the memory allocator with {} allocations still live", live);
+        }
+        for block in self.blocks.drain(..) {
+            Self::destroy_block(device, &block);
+        }
+    }
+
+    // The range to flush or invalidate: whole atoms, and never past the end of the block.
+    fn atom_range(&self, allocation: &Allocation, offset: vk::DeviceSize, size: vk::DeviceSize) -> vk::MappedMemoryRange<'static> {
+        let block_size = self
+            .blocks
+            .iter()
+            .find(|b| b.id == allocation.block)
+            .map_or(vk::WHOLE_SIZE, |b| b.size);
+        let size = if size == vk::WHOLE_SIZE { allocation.size - offset } else { size };
+        let start = align_down(allocation.offset + offset, self.non_coherent_atom_size);
+        let end = align_up(allocation.offset + offset + size, self.non_coherent_atom_size);
+        vk::MappedMemoryRange {
+            memory: allocation.memory,
+            offset: start,
+            size: if end >= block_size { vk::WHOLE_SIZE } else { end - start },
+            ..Default::default()
+        }
+    }
+
+    // Makes CPU writes visible to the device. Does nothing for coherent memory.
+    pub unsafe fn flush(&self, device: &Device, allocation: &Allocation, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<()> {
+        if allocation.coherent {
+            return Ok(());
+        }
+        device
+            .device
+            .flush_mapped_memory_ranges(&[self.atom_range(allocation, offset, size)])
+            .context("Could not flush mapped memory")
+    }
+
+    // Makes device writes visible to the CPU. Does nothing for coherent memory.
+    pub unsafe fn invalidate(&self, device: &Device, allocation: &Allocation, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<()> {
+        if allocation.coherent {
+            return Ok(());
+        }
+        device
+            .device
+            .invalidate_mapped_memory_ranges(&[self.atom_range(allocation, offset, size)])
+            .context("Could not invalidate mapped memory")
+    }
+
+    pub fn heap_stats(&self) -> Vec<HeapStats> {
+        let props = &self.memory_properties;
+        let mut stats: Vec<HeapStats> = props.memory_heaps[..props.memory_heap_count as usize]
+            .iter()
+            .enumerate()
+            .map(|(i, heap)| HeapStats {
+                heap: i as u32,
+                size: heap.size,
+                device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                ..Default::default()
+            })
+            .collect();
+
+        for block in self.blocks.iter() {
+            let heap = props.memory_types[block.memory_type as usize].heap_index as usize;
+            let s = &mut stats[heap];
+            s.block_count += 1;
+            s.block_bytes += block.size;
+            s.allocation_count += block.allocation_count;
+            s.allocated_bytes += block.size - block.free_bytes();
+        }
+        stats
+    }
+}
+
+impl Device {
+    // Picks a memory type for usage and sub-allocates from it. `linear` is true for buffers and
+    // linear-tiled images, false for optimal-tiled images.
+    pub unsafe fn allocate(&self, requirements: vk::MemoryRequirements, usage: MemoryUsage, linear: bool) -> Result<Allocation> {
+        self.allocator.borrow_mut().allocate(self, requirements, usage, linear)
+    }
+
+    pub unsafe fn free(&self, allocation: Allocation) {
+        self.allocator.borrow_mut().free(self, allocation)
+    }
+
+    pub unsafe fn allocate_for_buffer(&self, buffer: vk::Buffer, usage: MemoryUsage) -> Result<Allocation> {
+        let requirements = self.device.get_buffer_memory_requirements(buffer);
+        let allocation = self.allocate(requirements, usage, true)?;
+        if let Err(e) = self.device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+            self.free(allocation);
+            return Err(e).context("Could not bind buffer memory");
+        }
+        Ok(allocation)
+    }
+
+    pub unsafe fn allocate_for_image(&self, image: vk::Image, usage: MemoryUsage, linear: bool) -> Result<Allocation> {
+        let requirements = self.device.get_image_memory_requirements(image);
+        let allocation = self.allocate(requirements, usage, linear)?;
+        if let Err(e) = self.device.bind_image_memory(image, allocation.memory, allocation.offset) {
+            self.free(allocation);
+            return Err(e).context("Could not bind image memory");
+        }
+        Ok(allocation)
+    }
+
+    // Copies into host visible memory and flushes it.
+    pub unsafe fn write_memory(&self, allocation: &Allocation, offset: vk::DeviceSize, bytes: &[u8]) -> Result<()> {
+        if allocation.mapped.is_null() {
+            bail!("Writing to {:?} memory, which isn't host visible", allocation.usage);
+        }
+        if offset + bytes.len() as vk::DeviceSize > allocation.size {
+            bail!("Writing {} bytes at {} overruns a {} byte allocation", bytes.len(), offset, allocation.size);
+        }
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), allocation.mapped.add(offset as usize), bytes.len());
+        self.allocator.borrow().flush(self, allocation, offset, bytes.len() as vk::DeviceSize)
+    }
+
+    // Invalidates host visible memory and copies out of it.
+    pub unsafe fn read_memory(&self, allocation: &Allocation, offset: vk::DeviceSize, bytes: &mut [u8]) -> Result<()> {
+        if allocation.mapped.is_null() {
+            bail!("Reading from {:?} memory, which isn't host visible", allocation.usage);
+        }
+        if offset + bytes.len() as vk::DeviceSize > allocation.size {
+            bail!("Reading {} bytes at {} overruns a {} byte allocation", bytes.len(), offset, allocation.size);
+        }
+        self.allocator.borrow().invalidate(self, allocation, offset, bytes.len() as vk::DeviceSize)?;
+        std::ptr::copy_nonoverlapping(allocation.mapped.add(offset as usize), bytes.as_mut_ptr(), bytes.len());
+        Ok(())
+    }
+
+    // Our allocator's totals per heap, plus the driver's budget and usage when
+    // VK_EXT_memory_budget is enabled.
+    pub fn memory_stats(&self) -> Vec<HeapStats> {
+        let mut stats = self.allocator.borrow().heap_stats();
+
+        if self.has_extension(vk::ExtMemoryBudgetFn::name()) && self.supports_api(1, 1) {
+            let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+            let mut props2 = vk::PhysicalDeviceMemoryProperties2 {
+                p_next: &mut budget as *mut _ as *mut c_void,
+                ..Default::default()
+            };
+            unsafe {
+                self.loaders
+                    .instance
+                    .get_physical_device_memory_properties2(self.physical_device, &mut props2);
+            }
+            for s in stats.iter_mut() {
+                s.budget = Some(budget.heap_budget[s.heap as usize]);
+                s.usage = Some(budget.heap_usage[s.heap as usize]);
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: vk::DeviceSize, dedicated: bool) -> Block {
+        Block {
+            id: 0,
+            memory: vk::DeviceMemory::null(),
+            memory_type: 0,
+            linear: true,
+            dedicated,
+            size,
+            mapped: std::ptr::null_mut(),
+            free: vec![(0, size)],
+            allocation_count: 0,
+        }
+    }
+
+    #[test]
+    fn allocations_are_aligned_and_split_the_free_range() {
+        let mut b = block(1024, false);
+        assert_eq!(b.try_allocate(10, 1), Some(0));
+        assert_eq!(b.try_allocate(100, 256), Some(256));
+        // The gap left in front of the aligned allocation is still free.
+        assert_eq!(b.free, vec![(10, 246), (356, 668)]);
+        assert_eq!(b.try_allocate(200, 1), Some(10));
+        assert_eq!(b.allocation_count, 3);
+    }
+
+    #[test]
+    fn exhausted_block_refuses_allocations() {
+        let mut b = block(1024, false);
+        assert_eq!(b.try_allocate(1000, 1), Some(0));
+        assert_eq!(b.try_allocate(32, 1), None);
+        // There's room for the size but not at that alignment.
+        assert_eq!(b.try_allocate(8, 512), None);
+        assert_eq!(b.try_allocate(24, 1), Some(1000));
+        assert_eq!(b.try_allocate(1, 1), None);
+        assert_eq!(b.free_bytes(), 0);
+    }
+
+    #[test]
+    fn release_coalesces_free_neighbours() {
+        let mut b = block(300, false);
+        let offsets: Vec<_> = (0..3).map(|_| b.try_allocate(100, 1).unwrap()).collect();
+        b.release(offsets[0], 100);
+        b.release(offsets[2], 100);
+        assert_eq!(b.free, vec![(0, 100), (200, 100)]);
+        b.release(offsets[1], 100);
+        assert_eq!(b.free, vec![(0, 300)]);
+        assert_eq!(b.allocation_count, 0);
+        assert_eq!(b.try_allocate(300, 1), Some(0));
+    }
+
+    #[test]
+    fn dedicated_block_is_not_shared() {
+        let mut b = block(1024, true);
+        assert_eq!(b.try_allocate(512, 1), Some(0));
+        assert_eq!(b.try_allocate(16, 1), None);
+        b.release(0, 512);
+        assert_eq!(b.try_allocate(1024, 1), Some(0));
+    }
+
+    #[test]
+    fn atom_range_rounds_to_atoms_and_stops_at_the_block_end() {
+        let allocator = Allocator {
+            memory_properties: vk::PhysicalDeviceMemoryProperties::default(),
+            non_coherent_atom_size: 64,
+            blocks: vec![block(256, false)],
+            next_block_id: 1,
+        };
+        let allocation = |offset, size| Allocation {
+            memory: vk::DeviceMemory::null(),
+            offset,
+            size,
+            memory_type: 0,
+            usage: MemoryUsage::Upload,
+            coherent: false,
+            mapped: std::ptr::null_mut(),
+            block: 0,
+        };
+
+        let range = allocator.atom_range(&allocation(64, 64), 10, 20);
+        assert_eq!((range.offset, range.size), (64, 64));
+        let range = allocator.atom_range(&allocation(0, 128), 60, 10);
+        assert_eq!((range.offset, range.size), (0, 128));
+        // Rounding the end up would go past the block, which has to be WHOLE_SIZE instead.
+        let range = allocator.atom_range(&allocation(192, 60), 0, vk::WHOLE_SIZE);
+        assert_eq!((range.offset, range.size), (192, vk::WHOLE_SIZE));
+    }
+}
@@ -1,99 +1,213 @@
 use crate::device::*;
+use crate::handle::*;
+use crate::registry::*;
+use crate::upload::*;
 
 
+use anyhow::{Context, Result};
 use ash::prelude::*;
-use ash::vk;
+use ash::vk::{self, Handle};
 use std::default::Default;
 use std::rc::Rc;
 
 
+// A transient pool that is reset as a whole once the frame's submission is done, so nothing
+// recorded from it outlives the frame. Only one thread may use a pool at a time.
+pub struct FramePool {
+    pub pool: Owned<vk::CommandPool>,
+    // Secondary command buffers allocated so far. Kept across frames, since resetting the pool
+    // doesn't free them.
+    pub secondaries: Vec<vk::CommandBuffer>,
+}
+
+impl FramePool {
+    unsafe fn new(device: &Rc<Device>, name: &str) -> Result<FramePool> {
+        let pool = device
+            .device
+            .create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+                    .queue_family_index(device.graphics_queue_family),
+                device.allocation_callbacks(),
+            )
+            .context("Could not create frame command pool")?;
+        Ok(FramePool {
+            pool: Owned::new(device.clone(), pool).named(name),
+            secondaries: Vec::new(),
+        })
+    }
+
+    unsafe fn allocate(&self, level: vk::CommandBufferLevel, count: u32) -> VkResult<Vec<vk::CommandBuffer>> {
+        let device = &self.pool.parent;
+        let command_buffers = device.device.allocate_command_buffers(
+            &vk::CommandBufferAllocateInfo::default()
+                .command_pool(*self.pool)
+                .level(level)
+                .command_buffer_count(count),
+        )?;
+        for &command_buffer in &command_buffers {
+            register_pooled(&**device, self.pool.handle().as_raw(), command_buffer);
+        }
+        Ok(command_buffers)
+    }
+}
+
 pub struct PerFrame {
     pub device: Rc<Device>,
+    pub pool: FramePool,
     pub command_buffer: vk::CommandBuffer,
-    pub image_available_semaphore: vk::Semaphore,
-    pub render_finished_semaphore: vk::Semaphore,
-    pub in_flight_fence: vk::Fence,
+    // One per recording thread.
+    pub worker_pools: Vec<FramePool>,
+    pub image_available_semaphore: Owned<vk::Semaphore>,
+    pub render_finished_semaphore: Owned<vk::Semaphore>,
+    // Only without timelines, where it's what the frame's submission signals.
+    pub in_flight_fence: Option<Owned<vk::Fence>>,
+    // The value of the last submit that used this frame, or 0 if there hasn't been one.
+    pub submission: u64,
+    // For streaming uploads recorded into this frame's command buffer.
+    pub staging: StagingBuffer,
 }
 
+// Enough for per-frame constants and small dynamic meshes.
+pub const FRAME_STAGING_SIZE: vk::DeviceSize = 1 << 20;
+
 impl PerFrame {
-    pub fn new(device: Rc<Device>) -> VkResult<PerFrame> {
+    pub fn new(device: Rc<Device>, label: &str) -> Result<PerFrame> {
         unsafe {
-            let command_buffer = device.device.allocate_command_buffers(
-                &vk::CommandBufferAllocateInfo::default()
-                    .command_pool(device.command_pool)
-                    .level(vk::CommandBufferLevel::PRIMARY)
-                    .command_buffer_count(1),
-            );
-
-            let image_available_semaphore = match command_buffer {
-                Ok(_) => device
+            let semaphore = |name: &str| -> VkResult<Owned<vk::Semaphore>> {
+                let semaphore = device
                     .device
-                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None),
-                Err(e) => Err(e),
+                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), device.allocation_callbacks())?;
+                Ok(Owned::new(device.clone(), semaphore).named(&format!("{} {}", label, name)))
             };
-
-            let render_finished_semaphore = image_available_semaphore.and_then(|_| {
-                device
+            let image_available_semaphore =
+                semaphore("image_available_semaphore").context("Could not create semaphore")?;
+            let render_finished_semaphore =
+                semaphore("render_finished_semaphore").context("Could not create semaphore")?;
+
+            let in_flight_fence = if device.has_timelines() {
+                None
+            } else {
+                let fence = device
                     .device
-                    .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
-            });
-
-            let in_flight_fence = render_finished_semaphore.and_then(|_| {
-                device.device.create_fence(
-                    &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
-                    None,
-                )
-            });
-
-            if in_flight_fence.is_ok() {
-                return Ok(PerFrame {
-                    device,
-                    command_buffer: command_buffer.unwrap()[0],
-                    image_available_semaphore: image_available_semaphore.unwrap(),
-                    render_finished_semaphore: render_finished_semaphore.unwrap(),
-                    in_flight_fence: in_flight_fence.unwrap(),
-                });
-            }
-
-            if let Ok(f) = in_flight_fence {
-                device.device.destroy_fence(f, None);
-            }
+                    .create_fence(&vk::FenceCreateInfo::default(), device.allocation_callbacks())
+                    .context("Could not create fence")?;
+                Some(Owned::new(device.clone(), fence).named(&format!("{} in_flight_fence", label)))
+            };
 
-            if let Ok(s) = render_finished_semaphore {
-                device.device.destroy_semaphore(s, None);
-            }
+            let staging = StagingBuffer::new(&device, FRAME_STAGING_SIZE, &format!("{} staging", label));
+
+            // Command buffers are freed along with their pool, which the deletion queue only
+            // destroys once the last submit using them is done.
+            let pool = FramePool::new(&device, &format!("{} command_pool", label))?;
+            let command_buffer = pool
+                .allocate(vk::CommandBufferLevel::PRIMARY, 1)
+                .context("Could not allocate command buffer")?[0];
+            device.set_name(command_buffer, &format!("{} command_buffer", label));
+
+            let worker_pools = (0..device.config.recording_threads)
+                .map(|i| FramePool::new(&device, &format!("{} worker {} command_pool", label, i)))
+                .collect::<Result<Vec<FramePool>>>()?;
+
+            Ok(PerFrame {
+                device,
+                pool,
+                command_buffer,
+                worker_pools,
+                image_available_semaphore,
+                render_finished_semaphore,
+                in_flight_fence,
+                submission: 0,
+                staging,
+            })
+        }
+    }
 
-            if let Ok(s) = image_available_semaphore {
-                device.device.destroy_semaphore(s, None);
-            }
+    // Call once the last submission is done. Everything recorded the last time this frame was used
+    // goes back to the initial state.
+    pub unsafe fn reset(&mut self) -> VkResult<()> {
+        let dev = &self.device.device;
+        for pool in [&self.pool].into_iter().chain(self.worker_pools.iter()) {
+            dev.reset_command_pool(*pool.pool, vk::CommandPoolResetFlags::empty())?;
+        }
+        self.staging.reset();
+        Ok(())
+    }
 
-            if let Ok(c) = command_buffer {
-                device.device.free_command_buffers(device.command_pool, &c);
+    // Records job_count secondary command buffers for subpass of render_pass, spread over the
+    // worker pools on scoped threads. record gets the job's index and its command buffer,
+    // already begun. They come back in job order, for cmd_execute_commands in a render pass
+    // begun with SECONDARY_COMMAND_BUFFERS.
+    pub unsafe fn record_secondaries<F>(
+        &mut self,
+        render_pass: vk::RenderPass,
+        subpass: u32,
+        framebuffer: vk::Framebuffer,
+        job_count: usize,
+        record: F,
+    ) -> VkResult<Vec<vk::CommandBuffer>>
+    where
+        F: Fn(&ash::Device, vk::CommandBuffer, usize) + Sync,
+    {
+        if job_count == 0 {
+            return Ok(Vec::new());
+        }
+        assert!(!self.worker_pools.is_empty(), "No recording threads to record secondaries on");
+        let threads = self.worker_pools.len().min(job_count);
+
+        // Thread t records jobs t, t + threads, t + 2 * threads and so on. Allocating touches
+        // the pools, so it all happens up front on this thread.
+        for (t, pool) in self.worker_pools[..threads].iter_mut().enumerate() {
+            let needed = (t..job_count).step_by(threads).count();
+            if pool.secondaries.len() < needed {
+                let more = pool.allocate(vk::CommandBufferLevel::SECONDARY, (needed - pool.secondaries.len()) as u32)?;
+                pool.secondaries.extend(more);
             }
-
-            Err(in_flight_fence.unwrap_err())
         }
-    }
-}
 
-impl Drop for PerFrame {
-    fn drop(&mut self) {
-        unsafe {
-            let _ = self
-                .device
-                .device
-                .wait_for_fences(&[self.in_flight_fence], true, 100_000_000);
-            self.device
-                .device
-                .destroy_semaphore(self.image_available_semaphore, None);
-            self.device
-                .device
-                .destroy_semaphore(self.render_finished_semaphore, None);
-            self.device
-                .device
-                .free_command_buffers(self.device.command_pool, &[self.command_buffer]);
-            self.device.device.destroy_fence(self.in_flight_fence, None);
+        let dev = &self.device.device;
+        let record = &record;
+        let recorded: Vec<VkResult<Vec<(usize, vk::CommandBuffer)>>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = self.worker_pools[..threads]
+                .iter()
+                .enumerate()
+                .map(|(t, pool)| {
+                    let secondaries = &pool.secondaries;
+                    scope.spawn(move || {
+                        let inheritance = vk::CommandBufferInheritanceInfo::default()
+                            .render_pass(render_pass)
+                            .subpass(subpass)
+                            .framebuffer(framebuffer);
+                        let begin_info = vk::CommandBufferBeginInfo::default()
+                            .flags(
+                                vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT
+                                    | vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE,
+                            )
+                            .inheritance_info(&inheritance);
+
+                        let mut done = Vec::new();
+                        for (job, &command_buffer) in (t..job_count).step_by(threads).zip(secondaries.iter()) {
+                            dev.begin_command_buffer(command_buffer, &begin_info)?;
+                            record(dev, command_buffer, job);
+                            dev.end_command_buffer(command_buffer)?;
+                            done.push((job, command_buffer));
+                        }
+                        Ok(done)
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|w| w.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+
+        let mut command_buffers = Vec::with_capacity(job_count);
+        for result in recorded {
+            command_buffers.extend(result?);
         }
+        command_buffers.sort_by_key(|&(job, _)| job);
+        Ok(command_buffers.into_iter().map(|(_, command_buffer)| command_buffer).collect())
     }
 }
-
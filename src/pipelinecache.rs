@@ -0,0 +1,243 @@
+use ash::vk;
+use std::path::{Path, PathBuf};
+
+
+// Pipeline cache data saved between runs. The driver's own data starts with a
+// VkPipelineCacheHeaderVersionOne, which we check against the device before handing it back,
+// since not every driver copes with data from another device or driver version. It's wrapped
+// in a small header of ours with the length and a checksum, so a truncated or corrupt file is
+// caught before the driver sees it.
+
+const MAGIC: &[u8; 8] = b"VKTRIPC1";
+// Magic, data length and checksum.
+const WRAPPER_SIZE: usize = 8 + 8 + 8;
+// headerSize, headerVersion, vendorID, deviceID and pipelineCacheUUID.
+const DRIVER_HEADER_SIZE: usize = 4 + 4 + 4 + 4 + vk::UUID_SIZE;
+
+pub fn default_pipeline_cache_path() -> PathBuf {
+    std::env::temp_dir().join("vulkan-tutorial-pipeline-cache.bin")
+}
+
+// The data from an earlier run, or None if there's no file or it can't be used on this device.
+pub fn load_pipeline_cache(path: &Path, props: &vk::PhysicalDeviceProperties) -> Option<Vec<u8>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("No pipeline cache at {} yet", path.display());
+            return None;
+        }
+        Err(e) => {
+            log::warn!("Could not read pipeline cache {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    match unwrap_cache_file(&bytes, props) {
+        Ok(data) => {
+            log::info!("Loaded {} bytes of pipeline cache from {}", data.len(), path.display());
+            Some(data.to_vec())
+        }
+        Err(reason) => {
+            log::warn!("Ignoring pipeline cache {}: {}", path.display(), reason);
+            None
+        }
+    }
+}
+
+// Writes to a temporary file first, so a crash part way never leaves a truncated cache behind.
+pub fn save_pipeline_cache(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut bytes = Vec::with_capacity(WRAPPER_SIZE + data.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&fnv1a(data).to_le_bytes());
+    bytes.extend_from_slice(data);
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    std::fs::write(&temp, &bytes)?;
+    std::fs::rename(&temp, path)
+}
+
+fn unwrap_cache_file<'a>(bytes: &'a [u8], props: &vk::PhysicalDeviceProperties) -> Result<&'a [u8], String> {
+    if bytes.len() < WRAPPER_SIZE || &bytes[..8] != MAGIC {
+        return Err("not a pipeline cache file".to_string());
+    }
+    let length = read_u64(&bytes[8..16]);
+    let checksum = read_u64(&bytes[16..24]);
+    let data = &bytes[WRAPPER_SIZE..];
+    if length != data.len() as u64 {
+        return Err(format!("expected {} bytes of data but found {}", length, data.len()));
+    }
+    if checksum != fnv1a(data) {
+        return Err("checksum mismatch".to_string());
+    }
+
+    // The driver's header is always little endian.
+    if data.len() < DRIVER_HEADER_SIZE {
+        return Err("too short for a pipeline cache header".to_string());
+    }
+    let header_size = read_u32(&data[0..4]) as usize;
+    let header_version = read_u32(&data[4..8]) as i32;
+    let vendor_id = read_u32(&data[8..12]);
+    let device_id = read_u32(&data[12..16]);
+    let uuid = &data[16..DRIVER_HEADER_SIZE];
+    if header_size < DRIVER_HEADER_SIZE || header_size > data.len() {
+        return Err(format!("bad header size {}", header_size));
+    }
+    if header_version != vk::PipelineCacheHeaderVersion::ONE.as_raw() {
+        return Err(format!("unknown header version {}", header_version));
+    }
+    if vendor_id != props.vendor_id || device_id != props.device_id {
+        return Err(format!(
+            "made for device {:04x}:{:04x}, this is {:04x}:{:04x}",
+            vendor_id, device_id, props.vendor_id, props.device_id
+        ));
+    }
+    if uuid != &props.pipeline_cache_uuid[..] {
+        return Err("made by a different driver version".to_string());
+    }
+    Ok(data)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn props() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x2204,
+            pipeline_cache_uuid: [7; vk::UUID_SIZE],
+            ..Default::default()
+        }
+    }
+
+    // Driver data as a device with props would write it, with some payload after the header.
+    fn driver_data(props: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(DRIVER_HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_le_bytes());
+        data.extend_from_slice(&props.vendor_id.to_le_bytes());
+        data.extend_from_slice(&props.device_id.to_le_bytes());
+        data.extend_from_slice(&props.pipeline_cache_uuid);
+        data.extend_from_slice(b"compiled pipelines");
+        data
+    }
+
+    fn wrap(data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&fnv1a(data).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn rejection(bytes: &[u8]) -> String {
+        unwrap_cache_file(bytes, &props()).expect_err("Expected the file to be rejected")
+    }
+
+    #[test]
+    fn accepts_matching_data() {
+        let data = driver_data(&props());
+        assert_eq!(unwrap_cache_file(&wrap(&data), &props()), Ok(&data[..]));
+    }
+
+    #[test]
+    fn rejects_wrong_magic() {
+        let mut bytes = wrap(&driver_data(&props()));
+        bytes[0] = b'X';
+        assert!(rejection(&bytes).contains("not a pipeline cache file"));
+        assert!(rejection(b"VKTRIPC1").contains("not a pipeline cache file"));
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let bytes = wrap(&driver_data(&props()));
+        assert!(rejection(&bytes[..bytes.len() - 1]).contains("expected"));
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(rejection(&longer).contains("expected"));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut bytes = wrap(&driver_data(&props()));
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(rejection(&bytes).contains("checksum"));
+    }
+
+    #[test]
+    fn rejects_short_driver_header() {
+        let data = driver_data(&props());
+        assert!(rejection(&wrap(&data[..DRIVER_HEADER_SIZE - 1])).contains("too short"));
+    }
+
+    #[test]
+    fn rejects_bad_header_size() {
+        let mut data = driver_data(&props());
+        data[0..4].copy_from_slice(&(DRIVER_HEADER_SIZE as u32 - 1).to_le_bytes());
+        assert!(rejection(&wrap(&data)).contains("bad header size"));
+
+        data[0..4].copy_from_slice(&(data.len() as u32 + 1).to_le_bytes());
+        assert!(rejection(&wrap(&data)).contains("bad header size"));
+    }
+
+    #[test]
+    fn rejects_other_header_version() {
+        let mut data = driver_data(&props());
+        data[4..8].copy_from_slice(&2u32.to_le_bytes());
+        assert!(rejection(&wrap(&data)).contains("header version"));
+    }
+
+    #[test]
+    fn rejects_other_device() {
+        let mut other = props();
+        other.vendor_id = 0x1002;
+        assert!(rejection(&wrap(&driver_data(&other))).contains("made for device"));
+
+        let mut other = props();
+        other.device_id += 1;
+        assert!(rejection(&wrap(&driver_data(&other))).contains("made for device"));
+
+        let mut other = props();
+        other.pipeline_cache_uuid[15] = 0;
+        assert!(rejection(&wrap(&driver_data(&other))).contains("different driver version"));
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let dir = std::env::temp_dir().join(format!("vulkan-tutorial-pipelinecache-test-{}", std::process::id()));
+        let path = dir.join("cache.bin");
+        let data = driver_data(&props());
+
+        save_pipeline_cache(&path, &data).unwrap();
+        let loaded = load_pipeline_cache(&path, &props());
+        let mut other = props();
+        other.device_id += 1;
+        let loaded_elsewhere = load_pipeline_cache(&path, &other);
+        let missing = load_pipeline_cache(&dir.join("missing.bin"), &props());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded, Some(data));
+        assert_eq!(loaded_elsewhere, None);
+        assert_eq!(missing, None);
+    }
+}
@@ -0,0 +1,182 @@
+use crate::device::*;
+use crate::loaders::*;
+
+use ash::vk::{self, Handle};
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+
+// A record of every live Vulkan object, in debug builds only. Owned registers what it wraps,
+// and code that keeps raw handles registers them itself. Each one keeps its type, debug name,
+// the window it was made for and, with RUST_LIB_BACKTRACE=1 or RUST_BACKTRACE=1, a backtrace
+// from where it was created. Capturing one is slow, and command buffers and staging buffers
+// are registered every frame, so they're off otherwise. When a Device or Loaders drops,
+// anything of theirs still in here was never destroyed, and gets logged with all of that. In
+// release builds nothing is recorded and every count is 0.
+
+const ENABLED: bool = cfg!(debug_assertions);
+
+struct LiveObject {
+    object_type: vk::ObjectType,
+    // The pool it's freed along with, such as a command buffer's command pool, or 0.
+    pool: u64,
+    name: Option<String>,
+    owner: Option<String>,
+    backtrace: Backtrace,
+}
+
+// Keyed by the parent's handle and then the object's, since non-dispatchable handles are only
+// unique within one device.
+static LIVE: Mutex<BTreeMap<(u64, u64), LiveObject>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    static OWNER: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// What objects are created from: a Device, or the instance in Loaders.
+pub trait Parent {
+    fn parent_handle(&self) -> u64;
+}
+
+impl Parent for Device {
+    fn parent_handle(&self) -> u64 {
+        self.device.handle().as_raw()
+    }
+}
+
+impl Parent for Loaders {
+    fn parent_handle(&self) -> u64 {
+        self.instance.handle().as_raw()
+    }
+}
+
+// Objects registered on this thread while it's alive are put down to owner, usually a window's
+// label. Scopes nest.
+pub struct OwnerScope {
+    previous: Option<String>,
+}
+
+impl OwnerScope {
+    pub fn enter(owner: &str) -> OwnerScope {
+        let previous = OWNER.with(|o| o.replace(Some(owner.to_string())));
+        OwnerScope { previous }
+    }
+}
+
+impl Drop for OwnerScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        OWNER.with(|o| *o.borrow_mut() = previous);
+    }
+}
+
+pub fn register_object<H: Handle>(parent: &impl Parent, handle: H) {
+    register_pooled(parent, 0, handle);
+}
+
+// For objects allocated from a pool, which go when the pool is destroyed if they haven't been
+// freed before.
+pub fn register_pooled<H: Handle>(parent: &impl Parent, pool: u64, handle: H) {
+    if !ENABLED || handle.as_raw() == 0 {
+        return;
+    }
+    let object = LiveObject {
+        object_type: H::TYPE,
+        pool,
+        name: None,
+        owner: OWNER.with(|o| o.borrow().clone()),
+        backtrace: Backtrace::capture(),
+    };
+    LIVE.lock().unwrap().insert((parent.parent_handle(), handle.as_raw()), object);
+}
+
+pub fn unregister_object<H: Handle>(parent: &impl Parent, handle: H) {
+    if !ENABLED {
+        return;
+    }
+    let parent = parent.parent_handle();
+    let mut live = LIVE.lock().unwrap();
+    live.remove(&(parent, handle.as_raw()));
+    if matches!(H::TYPE, vk::ObjectType::COMMAND_POOL | vk::ObjectType::DESCRIPTOR_POOL) {
+        live.retain(|&(p, _), object| p != parent || object.pool != handle.as_raw());
+    }
+}
+
+// Only for objects that are already registered.
+pub fn name_object<H: Handle>(parent: &impl Parent, handle: H, name: &str) {
+    if !ENABLED {
+        return;
+    }
+    if let Some(object) = LIVE.lock().unwrap().get_mut(&(parent.parent_handle(), handle.as_raw())) {
+        object.name = Some(name.to_string());
+    }
+}
+
+// Across every device and instance.
+pub fn objects_alive() -> usize {
+    LIVE.lock().unwrap().len()
+}
+
+fn objects_alive_for(parent: u64) -> usize {
+    LIVE.lock().unwrap().range((parent, 0)..=(parent, u64::MAX)).count()
+}
+
+// Logs everything of parent's that's still registered, and forgets it, since it can't be
+// destroyed any more. what says what's being destroyed, for the log.
+pub fn report_live_objects(parent: &impl Parent, what: &str) {
+    let parent = parent.parent_handle();
+    let leaked: Vec<((u64, u64), LiveObject)> = {
+        let mut live = LIVE.lock().unwrap();
+        let keys: Vec<(u64, u64)> = live.range((parent, 0)..=(parent, u64::MAX)).map(|(&k, _)| k).collect();
+        keys.into_iter().map(|k| (k, live.remove(&k).unwrap())).collect()
+    };
+    if leaked.is_empty() {
+        return;
+    }
+
+    let lines: Vec<String> = leaked
+        .iter()
+        .map(|((_, handle), object)| {
+            let created = match object.backtrace.status() {
+                BacktraceStatus::Captured => format!(", created at:\n{}", object.backtrace),
+                _ => String::new(),
+            };
+            format!(
+                "{:?} {:#x} \"{}\" for {}{}",
+                object.object_type,
+                handle,
+                object.name.as_deref().unwrap_or("unnamed"),
+                object.owner.as_deref().unwrap_or("no window"),
+                created
+            )
+        })
+        .collect();
+    let hint = if leaked.iter().any(|(_, o)| o.backtrace.status() == BacktraceStatus::Captured) {
+        ""
+    } else {
+        "\nRun with RUST_LIB_BACKTRACE=1 to see where they were created."
+    };
+    log::warn!(
+        "{} Vulkan objects were still alive when the {} was destroyed:\n{}{}",
+        leaked.len(),
+        what,
+        lines.join("\n"),
+        hint
+    );
+}
+
+impl Device {
+    // Objects made from this device that haven't been destroyed yet, counting ones waiting in
+    // the deletion queue.
+    pub fn objects_alive(&self) -> usize {
+        objects_alive_for(self.parent_handle())
+    }
+}
+
+impl Loaders {
+    pub fn objects_alive(&self) -> usize {
+        objects_alive_for(self.parent_handle())
+    }
+}
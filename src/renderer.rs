@@ -1,7 +1,8 @@
 use crate::config::*;
 use crate::device::*;
 use crate::swapsurface::*;
-use crate::util::as_byte_slice;
+use crate::handle::*;
+use crate::util::{as_byte_slice, slice_as_bytes};
 use crate::window::*;
 
 use anyhow::{Context, Result};
@@ -54,6 +55,11 @@ impl Vertex {
     }
 }
 
+const LABEL_FRAME: [f32; 4] = [0.8, 0.8, 0.8, 1.0];
+const LABEL_PASS: [f32; 4] = [0.2, 0.4, 0.9, 1.0];
+const LABEL_BIND: [f32; 4] = [0.9, 0.6, 0.2, 1.0];
+const LABEL_DRAW: [f32; 4] = [0.2, 0.8, 0.3, 1.0];
+
 static TRIANGLE: &'static [Vertex] = &[
     Vertex {
         pos: const_vec2!([0.0, -0.5]),
@@ -69,15 +75,85 @@ static TRIANGLE: &'static [Vertex] = &[
     },
 ];
 
+// Everything the draw needs, so it can be recorded on a worker thread as well as inline.
+#[derive(Clone, Copy)]
+struct TriangleDraw {
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    vertex_buffer: vk::Buffer,
+    // Set when the viewport and scissor are dynamic. Secondaries inherit neither.
+    viewport_extent: Option<vk::Extent2D>,
+    push_constants: Vec4,
+}
+
+impl TriangleDraw {
+    // Draws the part of the triangle in band of band_count horizontal bands of the framebuffer,
+    // so the bands can be recorded on different threads. Needs dynamic scissor for more than one.
+    unsafe fn record(
+        &self,
+        dev: &ash::Device,
+        labels: CmdLabels,
+        command_buffer: vk::CommandBuffer,
+        band: usize,
+        band_count: usize,
+    ) {
+        labels.begin(command_buffer, "bind pipeline", LABEL_BIND);
+        dev.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+        dev.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer], &[0]);
+
+        if let Some(extent) = self.viewport_extent {
+            dev.cmd_set_viewport(
+                command_buffer,
+                0,
+                &[vk::Viewport {
+                    x: 0.0,
+                    y: 0.0,
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    min_depth: 0.0,
+                    max_depth: 1.,
+                }],
+            );
+
+            let top = extent.height as usize * band / band_count;
+            let bottom = extent.height as usize * (band + 1) / band_count;
+            dev.cmd_set_scissor(
+                command_buffer,
+                0,
+                &[vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: top as i32 },
+                    extent: vk::Extent2D {
+                        width: extent.width,
+                        height: (bottom - top) as u32,
+                    },
+                }],
+            );
+        } else {
+            debug_assert_eq!(band_count, 1, "Drawing in bands needs a dynamic scissor");
+        }
+        labels.end(command_buffer);
+
+        labels.begin(command_buffer, format_args!("draw triangle, band {} of {}", band + 1, band_count), LABEL_DRAW);
+        dev.cmd_push_constants(
+            command_buffer,
+            self.pipeline_layout,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            0,
+            as_byte_slice(&self.push_constants),
+        );
+        dev.cmd_draw(command_buffer, 3, 1, 0, 0);
+        labels.end(command_buffer);
+    }
+}
+
 pub struct Renderer {
     pub device: Rc<Device>,
-    pub renderpass: vk::RenderPass,
-    pub vertex_shader_module: vk::ShaderModule,
-    pub fragment_shader_module: vk::ShaderModule,
-    pub pipeline_layout: vk::PipelineLayout,
-    pub pipeline: vk::Pipeline,
-    pub vertex_buffer: vk::Buffer,
-    pub vertex_buffer_memory: vk::DeviceMemory,
+    pub pipeline: Owned<vk::Pipeline>,
+    pub renderpass: Owned<vk::RenderPass>,
+    pub pipeline_layout: Owned<vk::PipelineLayout>,
+    pub vertex_shader_module: Owned<vk::ShaderModule>,
+    pub fragment_shader_module: Owned<vk::ShaderModule>,
+    pub vertex_buffer: Buffer,
     pub start_time: std::time::SystemTime
 }
 
@@ -114,27 +190,33 @@ impl Renderer {
             .subpasses(&subpass)
             .dependencies(&subpass_dependencies);
 
-        let renderpass = device.device.create_render_pass(&renderpass_info, None)?;
+        let renderpass = device.device.create_render_pass(&renderpass_info, device.allocation_callbacks())?;
+        let renderpass = Owned::new(device.clone(), renderpass).named("triangle renderpass");
 
+        // Both shaders read the same push constant block.
         let push_constant_ranges = [vk::PushConstantRange {
-            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
             size: 16,
             offset: 0
         }];
         let pipeline_layout = device
             .device
             .create_pipeline_layout(&vk::PipelineLayoutCreateInfo::default()
-                .push_constant_ranges(&push_constant_ranges), None)
+                .push_constant_ranges(&push_constant_ranges), device.allocation_callbacks())
             .context("Could not create pipeline layout")?;
+        let pipeline_layout = Owned::new(device.clone(), pipeline_layout).named("triangle pipeline layout");
 
-        let create_shader_module = |bytecode| {
+        let create_shader_module = |bytecode, name| {
             let code = transmute_many::<u32, PedanticGuard>(bytecode).unwrap();
             let shadermodule_info = vk::ShaderModuleCreateInfo::default().code(code);
-            device.device.create_shader_module(&shadermodule_info, None)
+            device
+                .device
+                .create_shader_module(&shadermodule_info, device.allocation_callbacks())
+                .map(|module| Owned::new(device.clone(), module).named(name))
         };
-        let vertex_shader_module =
-            create_shader_module(&VERTEX_BYTECODE).context("Could not create vertex bytecode")?;
-        let fragment_shader_module = create_shader_module(&FRAGMENT_BYTECODE)
+        let vertex_shader_module = create_shader_module(&VERTEX_BYTECODE, "triangle vertex shader")
+            .context("Could not create vertex bytecode")?;
+        let fragment_shader_module = create_shader_module(&FRAGMENT_BYTECODE, "triangle fragment shader")
             .context("Could not create fragment bytecode")?;
 
         let create_shader_stage = |module, stage| {
@@ -145,8 +227,8 @@ impl Renderer {
         };
 
         let shader_stages = [
-            create_shader_stage(vertex_shader_module, vk::ShaderStageFlags::VERTEX),
-            create_shader_stage(fragment_shader_module, vk::ShaderStageFlags::FRAGMENT),
+            create_shader_stage(*vertex_shader_module, vk::ShaderStageFlags::VERTEX),
+            create_shader_stage(*fragment_shader_module, vk::ShaderStageFlags::FRAGMENT),
         ];
 
         let vertex_desc = Vertex::get_description();
@@ -202,7 +284,7 @@ impl Renderer {
             vk::PipelineColorBlendStateCreateInfo::default().attachments(&blendattachment_info);
 
         let mut dyn_states = Vec::<vk::DynamicState>::new();
-        if VK_DYNAMIC_VIEW_SIZE {
+        if device.config.dynamic_viewport {
             dyn_states.push(vk::DynamicState::VIEWPORT);
             dyn_states.push(vk::DynamicState::SCISSOR);
         }
@@ -216,89 +298,34 @@ impl Renderer {
             .rasterization_state(&rasterizer_info)
             .multisample_state(&multisample_info)
             .color_blend_state(&colorblend_info)
-            .layout(pipeline_layout)
-            .render_pass(renderpass)
+            .layout(*pipeline_layout)
+            .render_pass(*renderpass)
             .dynamic_state(&dyn_state)
             .subpass(0);
 
         let pipeline = device
             .device
-            .create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
-            .unwrap()[0];
-
-        let vertex_buffer = device
-            .device
-            .create_buffer(
-                &vk::BufferCreateInfo::default()
-                    .size(mem::size_of_val(TRIANGLE) as u64)
-                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                None,
-            )
-            .context("Creating vertex buffer")?;
-
-        let mem_reqs = device.device.get_buffer_memory_requirements(vertex_buffer);
-        dbg!(mem_reqs);
-        let mem_props = device
-            .loaders
-            .instance
-            .get_physical_device_memory_properties(device.physical_device);
-        dbg!(mem_props);
-
-        let type_index = (0..mem_props.memory_type_count)
-            .find(|&type_index| {
-                mem_reqs.memory_type_bits & (1 << type_index) != 0
-                    && mem_props.memory_types[type_index as usize]
-                        .property_flags
-                        .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
-            })
-            .context("Could not find a memory type for the vertex buffer")?;
-
-        let vertex_buffer_memory = device
-            .device
-            .allocate_memory(
-                &vk::MemoryAllocateInfo::default()
-                    .allocation_size(mem_reqs.size)
-                    .memory_type_index(type_index),
-                None,
-            )
-            .context("Could not allocate vertex buffer memory")?;
-
-        device
-            .device
-            .bind_buffer_memory(vertex_buffer, vertex_buffer_memory, 0)
-            .context("Binding vertex buffer memory")?;
-
-        let map_ptr = device
-            .device
-            .map_memory(
-                vertex_buffer_memory,
-                0,
-                vk::WHOLE_SIZE,
-                vk::MemoryMapFlags::empty(),
-            )
-            .context("Mapping vertex buffer memory")?;
-
-        std::ptr::copy_nonoverlapping(TRIANGLE.as_ptr(), map_ptr as *mut Vertex, TRIANGLE.len());
-
-        device.device.flush_mapped_memory_ranges(&[vk::MappedMemoryRange {
-            memory: vertex_buffer_memory,
-            offset: 0,
-            size: vk::WHOLE_SIZE,
-            ..Default::default()
-        }]).context("Flushing caches")?;
-
-        device.device.unmap_memory(vertex_buffer_memory);
+            .create_graphics_pipelines(device.pipeline_cache, &[pipeline_info], device.allocation_callbacks())
+            .map_err(|(_, e)| e)
+            .context("Could not create pipeline")?[0];
+        let pipeline = Owned::new(device.clone(), pipeline).named("triangle pipeline");
+
+        let (vertex_buffer, upload) = Buffer::device_local(
+            &device,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            slice_as_bytes(TRIANGLE),
+            "triangle vertex buffer",
+        )?;
+        device.wait_upload(upload).context("Uploading vertex data")?;
 
         Ok(Renderer {
             device,
+            pipeline,
             renderpass,
+            pipeline_layout,
             vertex_shader_module,
             fragment_shader_module,
-            pipeline_layout,
-            pipeline,
             vertex_buffer,
-            vertex_buffer_memory,
             start_time: std::time::SystemTime::now()
         })
     }
@@ -306,9 +333,18 @@ impl Renderer {
     pub unsafe fn render(&mut self, win: &mut VulkanWindow) -> VkResult<()> {
         let dev: &ash::Device = &self.device.device;
 
-        let pf = &win.per_frame[win.frame_count % win.per_frame.len()];
+        if let Some((frame, fault)) = win.injected_fault {
+            if win.frame_count >= frame {
+                win.injected_fault = None;
+                return Err(fault);
+            }
+        }
+
+        let pf = &mut win.per_frame[win.frame_count % win.per_frame.len()];
 
-        dev.wait_for_fences(&[pf.in_flight_fence], true, u64::max_value())?;
+        self.device.wait_submission(pf.submission, u64::MAX)?;
+        self.device.collect_garbage();
+        pf.reset()?;
 
         win.frame_count += 1;
         let now = std::time::Instant::now();
@@ -329,117 +365,144 @@ impl Renderer {
         let (swap_index, _) = self.device.swapchain_loader.acquire_next_image(
             win.swap.swapchain,
             u64::MAX,
-            pf.image_available_semaphore,
+            *pf.image_available_semaphore,
             vk::Fence::null(),
         )?;
 
-        dev.reset_command_buffer(pf.command_buffer, vk::CommandBufferResetFlags::empty())?;
+        let frame = win.frame_count;
+        let device = &self.device;
 
+        let time = std::time::Instant::now().duration_since(win.anim_start_time).as_secs_f64() as f32;
+        let min_dim = std::cmp::min(win.swap.size.width, win.swap.size.height) as f32;
+        let draw = TriangleDraw {
+            pipeline: *self.pipeline,
+            pipeline_layout: *self.pipeline_layout,
+            vertex_buffer: self.vertex_buffer.handle(),
+            viewport_extent: if device.config.dynamic_viewport {
+                Some(win.swap.size)
+            } else {
+                None
+            },
+            push_constants: vec4(
+                min_dim / win.swap.size.width as f32,
+                min_dim / win.swap.size.height as f32,
+                time * win.shape_rotate_speed,
+                time * win.color_rotate_speed
+            ),
+        };
+        let framebuffer = win.swap.framebuffers[swap_index as usize].handle();
+
+        // With recording threads the draw goes in secondary command buffers, recorded before
+        // the primary even begins. With a dynamic scissor each thread gets a band of its own.
+        let secondaries = if pf.worker_pools.is_empty() {
+            None
+        } else {
+            let bands = if draw.viewport_extent.is_some() { pf.worker_pools.len() } else { 1 };
+            let labels = device.cmd_labels();
+            Some(pf.record_secondaries(*self.renderpass, 0, framebuffer, bands, |dev, command_buffer, band| {
+                draw.record(dev, labels, command_buffer, band, bands)
+            })?)
+        };
+
+        let labels = device.cmd_labels();
         dev.begin_command_buffer(pf.command_buffer, &vk::CommandBufferBeginInfo::default())?;
+        labels.begin(pf.command_buffer, format_args!("{} frame {}", win.label, frame), LABEL_FRAME);
+        // A subpass that executes secondaries can't have label commands of its own, so there the
+        // primary's region has to cover the whole pass, and the secondaries label the draw.
+        let pass_label = match secondaries {
+            Some(_) => "render pass",
+            None => "clear and begin render pass",
+        };
+        labels.begin(pf.command_buffer, pass_label, LABEL_PASS);
         dev.cmd_begin_render_pass(
             pf.command_buffer,
             &vk::RenderPassBeginInfo::default()
-                .render_pass(self.renderpass)
-                .framebuffer(win.swap.framebuffers[swap_index as usize])
+                .render_pass(*self.renderpass)
+                .framebuffer(framebuffer)
                 .render_area(win.swap.size.into())
                 .clear_values(&[vk::ClearValue {
                     color: vk::ClearColorValue {
                         float32: win.background_color
                     },
                 }]),
-            vk::SubpassContents::INLINE,
-        );
-
-        dev.cmd_bind_pipeline(
-            pf.command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            self.pipeline,
+            match secondaries {
+                Some(_) => vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
+                None => vk::SubpassContents::INLINE,
+            },
         );
 
-        dev.cmd_bind_vertex_buffers(pf.command_buffer, 0, &[self.vertex_buffer], &[0]);
-
-        if VK_DYNAMIC_VIEW_SIZE {
-            dev.cmd_set_viewport(
-                pf.command_buffer,
-                0,
-                &[vk::Viewport {
-                    x: 0.0,
-                    y: 0.0,
-                    width: win.swap.size.width as f32,
-                    height: win.swap.size.height as f32,
-                    min_depth: 0.0,
-                    max_depth: 1.,
-                }],
-            );
-
-            dev.cmd_set_scissor(
-                pf.command_buffer,
-                0,
-                &[vk::Rect2D {
-                    offset: vk::Offset2D { x: 0, y: 0 },
-                    extent: win.swap.size,
-                }],
-            );
+        match &secondaries {
+            Some(secondaries) => {
+                dev.cmd_execute_commands(pf.command_buffer, secondaries);
+                dev.cmd_end_render_pass(pf.command_buffer);
+                labels.end(pf.command_buffer);
+            }
+            None => {
+                labels.end(pf.command_buffer);
+                draw.record(dev, labels, pf.command_buffer, 0, 1);
+                labels.begin(pf.command_buffer, "end render pass", LABEL_PASS);
+                dev.cmd_end_render_pass(pf.command_buffer);
+                labels.end(pf.command_buffer);
+            }
         }
-
-        let time = std::time::Instant::now().duration_since(win.anim_start_time).as_secs_f64() as f32;
-        let min_dim = std::cmp::min(win.swap.size.width, win.swap.size.height) as f32;
-        let pcs = vec4(
-            min_dim / win.swap.size.width as f32,
-            min_dim / win.swap.size.height as f32,
-            time * win.shape_rotate_speed,
-            time * win.color_rotate_speed
-        );
-        
-        dev.cmd_push_constants(pf.command_buffer, self.pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0, as_byte_slice(&pcs));
-        dev.cmd_draw(pf.command_buffer, 3, 1, 0, 0);
-        dev.cmd_end_render_pass(pf.command_buffer);
+        labels.end(pf.command_buffer);
         dev.end_command_buffer(pf.command_buffer)?;
 
-        dev.reset_fences(&[pf.in_flight_fence])?;
-        dev.queue_submit(
-            self.device.graphics_queue,
-            &[vk::SubmitInfo::default()
-                .wait_semaphores(&[pf.image_available_semaphore])
-                .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
-                .command_buffers(&[pf.command_buffer])
-                .signal_semaphores(&[pf.render_finished_semaphore])],
-            pf.in_flight_fence,
-        )?;
+        let fence = pf.in_flight_fence.as_ref().map_or(vk::Fence::null(), |f| f.handle());
+        if fence != vk::Fence::null() {
+            dev.reset_fences(&[fence])?;
+        }
+        device.queue_begin_label(device.graphics_queue, format_args!("{} frame {} submit", win.label, frame));
+        let submitted = device.submit(
+            device.graphics_queue,
+            &[pf.command_buffer],
+            &[(*pf.image_available_semaphore, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)],
+            &[*pf.render_finished_semaphore],
+            fence,
+        );
+        device.queue_end_label(device.graphics_queue);
+        pf.submission = submitted?;
 
-        self.device.swapchain_loader.queue_present(
-            self.device.present_queue,
+        let present_queue = win.swap.present_queue;
+        device.queue_begin_label(present_queue, format_args!("{} frame {} present", win.label, frame));
+        let presented = device.swapchain_loader.queue_present(
+            present_queue,
             &vk::PresentInfoKHR::default()
-                .wait_semaphores(&[pf.render_finished_semaphore])
+                .wait_semaphores(&[*pf.render_finished_semaphore])
                 .swapchains(&[win.swap.swapchain])
                 .image_indices(&[swap_index]),
-        )?;
+        );
+        device.queue_end_label(present_queue);
+        presented?;
 
         Result::Ok(())
     }
-}
 
-impl Drop for Renderer {
-    fn drop(&mut self) {
-        unsafe {
-            let _ = self.device.device.device_wait_idle();
-            self.device.device.destroy_pipeline(self.pipeline, None);
-            self.device
-                .device
-                .destroy_render_pass(self.renderpass, None);
-            self.device
-                .device
-                .destroy_pipeline_layout(self.pipeline_layout, None);
-            self.device
-                .device
-                .destroy_shader_module(self.fragment_shader_module, None);
-            self.device
-                .device
-                .destroy_shader_module(self.vertex_shader_module, None);
-            self.device.device.destroy_buffer(self.vertex_buffer, None);
-            self.device
-                .device
-                .free_memory(self.vertex_buffer_memory, None);
+    // Brings everything back after ERROR_DEVICE_LOST: a new Device on the same Loaders and
+    // config, a new Renderer, and new swapchains and per-frame objects for every window. The
+    // old device goes once the last thing made from it has dropped.
+    pub unsafe fn recover_device_lost(&mut self, windows: &mut [&mut VulkanWindow]) -> Result<()> {
+        log::warn!("Device lost, rebuilding the device, renderer and {} windows", windows.len());
+
+        // The renderer needs a swapchain to pick its render pass format from.
+        let (first, rest) = windows
+            .split_first_mut()
+            .context("Can't rebuild the renderer without a window")?;
+
+        let device = Rc::new(
+            Device::create(self.device.loaders.clone(), first.surface.surface, self.device.config.clone())
+                .context("Could not create a new device")?,
+        );
+
+        first.rebuild(device.clone(), None)?;
+        let renderer = Renderer::new(device.clone(), &first.swap).context("Could not create a new Renderer")?;
+        first.swap.create_framebuffers(&renderer).context("Could not create framebuffers")?;
+
+        for w in rest.iter_mut() {
+            w.rebuild(device.clone(), Some(&renderer))?;
         }
+
+        *self = renderer;
+        Ok(())
     }
 }
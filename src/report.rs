@@ -0,0 +1,403 @@
+use crate::features::*;
+use crate::loaders::*;
+use crate::util::*;
+
+use anyhow::{Context, Result};
+use ash::vk;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+
+// Everything we know about the Vulkan installation, in one place so it can be printed for
+// humans or dumped as JSON and diffed between driver versions.
+
+#[derive(Serialize)]
+pub struct VkInfoReport {
+    pub instance: InstanceReport,
+    pub devices: Vec<DeviceReport>,
+}
+
+#[derive(Serialize)]
+pub struct InstanceReport {
+    pub loader_api_version: String,
+    pub enabled_layers: Vec<String>,
+    pub enabled_extensions: Vec<String>,
+    pub layers: Vec<LayerReport>,
+    pub extensions: Vec<ExtensionReport>,
+}
+
+#[derive(Serialize)]
+pub struct LayerReport {
+    pub name: String,
+    pub description: String,
+    pub spec_version: String,
+    pub implementation_version: u32,
+}
+
+#[derive(Serialize)]
+pub struct ExtensionReport {
+    pub name: String,
+    pub spec_version: u32,
+}
+
+#[derive(Serialize)]
+pub struct DeviceReport {
+    pub index: usize,
+    pub name: String,
+    pub device_type: String,
+    pub api_version: String,
+    pub driver_version: u32,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub pipeline_cache_uuid: String,
+    pub limits: BTreeMap<&'static str, u64>,
+    pub features: BTreeMap<&'static str, bool>,
+    pub queue_families: Vec<QueueFamilyReport>,
+    pub extensions: Vec<ExtensionReport>,
+    pub memory_heaps: Vec<MemoryHeapReport>,
+    pub memory_types: Vec<MemoryTypeReport>,
+    pub surface: Option<SurfaceReport>,
+    // Whatever couldn't be queried, which is left empty or None above.
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct QueueFamilyReport {
+    pub index: u32,
+    pub flags: String,
+    pub queue_count: u32,
+    pub timestamp_valid_bits: u32,
+    pub min_image_transfer_granularity: [u32; 3],
+    pub supports_present: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct MemoryHeapReport {
+    pub index: u32,
+    pub size: u64,
+    pub flags: String,
+}
+
+#[derive(Serialize)]
+pub struct MemoryTypeReport {
+    pub index: u32,
+    pub heap_index: u32,
+    pub flags: String,
+}
+
+#[derive(Serialize)]
+pub struct SurfaceReport {
+    pub min_image_count: u32,
+    pub max_image_count: u32,
+    pub current_extent: [u32; 2],
+    pub min_image_extent: [u32; 2],
+    pub max_image_extent: [u32; 2],
+    pub supported_usage: String,
+    pub supported_transforms: String,
+    pub supported_composite_alpha: String,
+    pub formats: Vec<String>,
+    pub present_modes: Vec<String>,
+}
+
+fn extension_reports(props: &[vk::ExtensionProperties]) -> Vec<ExtensionReport> {
+    props
+        .iter()
+        .map(|p| ExtensionReport {
+            name: cstr_string(&p.extension_name),
+            spec_version: p.spec_version,
+        })
+        .collect()
+}
+
+impl VkInfoReport {
+    pub unsafe fn collect(loaders: &Loaders, surface: Option<vk::SurfaceKHR>) -> Result<VkInfoReport> {
+        let entry = &loaders.entry;
+        let instance = &loaders.instance;
+
+        let loader_version = entry
+            .try_enumerate_instance_version()
+            .context("Could not get Vulkan version")?
+            .unwrap_or(vk::make_api_version(0, 1, 0, 0));
+
+        let layers = entry
+            .enumerate_instance_layer_properties()
+            .context("Could not enumerate instance layers")?
+            .iter()
+            .map(|p| LayerReport {
+                name: cstr_string(&p.layer_name),
+                description: cstr_string(&p.description),
+                spec_version: version_string(p.spec_version),
+                implementation_version: p.implementation_version,
+            })
+            .collect();
+
+        let extensions = extension_reports(
+            &entry
+                .enumerate_instance_extension_properties(None)
+                .context("Could not enumerate instance extensions")?,
+        );
+
+        let instance_report = InstanceReport {
+            loader_api_version: version_string(loader_version),
+            enabled_layers: loaders
+                .enabled_layers
+                .iter()
+                .map(|l| l.to_string_lossy().into_owned())
+                .collect(),
+            enabled_extensions: loaders
+                .enabled_extensions
+                .iter()
+                .map(|e| e.to_string_lossy().into_owned())
+                .collect(),
+            layers,
+            extensions,
+        };
+
+        let physical_devices = instance
+            .enumerate_physical_devices()
+            .context("Could not enumerate physical devices")?;
+
+        let mut devices = Vec::new();
+        for (index, &dev) in physical_devices.iter().enumerate() {
+            devices.push(Self::collect_device(loaders, index, dev, surface));
+        }
+
+        Ok(VkInfoReport {
+            instance: instance_report,
+            devices,
+        })
+    }
+
+    unsafe fn collect_device(
+        loaders: &Loaders,
+        index: usize,
+        dev: vk::PhysicalDevice,
+        surface: Option<vk::SurfaceKHR>,
+    ) -> DeviceReport {
+        let instance = &loaders.instance;
+        let mut errors = Vec::new();
+        let props = instance.get_physical_device_properties(dev);
+        let features = DeviceFeatures::query(instance, dev, api_version_min(loaders.api_version, props.api_version));
+        let queues = instance.get_physical_device_queue_family_properties(dev);
+        let mem_props = instance.get_physical_device_memory_properties(dev);
+        let extensions = instance
+            .enumerate_device_extension_properties(dev)
+            .context("Could not enumerate device extensions")
+            .unwrap_or_else(|e| {
+                errors.push(format!("{:#}", e));
+                Vec::new()
+            });
+
+        let l = &props.limits;
+        let limits = BTreeMap::from([
+            ("max_image_dimension2_d", l.max_image_dimension2_d as u64),
+            ("max_push_constants_size", l.max_push_constants_size as u64),
+            ("max_memory_allocation_count", l.max_memory_allocation_count as u64),
+            ("max_bound_descriptor_sets", l.max_bound_descriptor_sets as u64),
+            ("max_color_attachments", l.max_color_attachments as u64),
+            ("max_viewports", l.max_viewports as u64),
+            ("max_compute_shared_memory_size", l.max_compute_shared_memory_size as u64),
+            ("buffer_image_granularity", l.buffer_image_granularity),
+            ("non_coherent_atom_size", l.non_coherent_atom_size),
+            ("min_uniform_buffer_offset_alignment", l.min_uniform_buffer_offset_alignment),
+            ("min_storage_buffer_offset_alignment", l.min_storage_buffer_offset_alignment),
+            ("optimal_buffer_copy_offset_alignment", l.optimal_buffer_copy_offset_alignment),
+            ("timestamp_compute_and_graphics", l.timestamp_compute_and_graphics as u64),
+        ]);
+
+        let queue_families = queues
+            .iter()
+            .enumerate()
+            .map(|(i, q)| QueueFamilyReport {
+                index: i as u32,
+                flags: format!("{:?}", q.queue_flags),
+                queue_count: q.queue_count,
+                timestamp_valid_bits: q.timestamp_valid_bits,
+                min_image_transfer_granularity: [
+                    q.min_image_transfer_granularity.width,
+                    q.min_image_transfer_granularity.height,
+                    q.min_image_transfer_granularity.depth,
+                ],
+                supports_present: surface.map(|surface| {
+                    loaders
+                        .surface
+                        .get_physical_device_surface_support(dev, i as u32, surface)
+                        == Ok(true)
+                }),
+            })
+            .collect();
+
+        let memory_heaps = mem_props.memory_heaps[..mem_props.memory_heap_count as usize]
+            .iter()
+            .enumerate()
+            .map(|(i, heap)| MemoryHeapReport {
+                index: i as u32,
+                size: heap.size,
+                flags: format!("{:?}", heap.flags),
+            })
+            .collect();
+
+        let memory_types = mem_props.memory_types[..mem_props.memory_type_count as usize]
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| MemoryTypeReport {
+                index: i as u32,
+                heap_index: ty.heap_index,
+                flags: format!("{:?}", ty.property_flags),
+            })
+            .collect();
+
+        let surface = surface.and_then(|surface| match Self::collect_surface(loaders, dev, surface) {
+            Ok(report) => Some(report),
+            Err(e) => {
+                errors.push(format!("{:#}", e));
+                None
+            }
+        });
+
+        DeviceReport {
+            index,
+            name: cstr_string(&props.device_name),
+            device_type: format!("{:?}", props.device_type),
+            api_version: version_string(props.api_version),
+            driver_version: props.driver_version,
+            vendor_id: props.vendor_id,
+            device_id: props.device_id,
+            pipeline_cache_uuid: props
+                .pipeline_cache_uuid
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            limits,
+            features: features.list().into_iter().collect(),
+            queue_families,
+            extensions: extension_reports(&extensions),
+            memory_heaps,
+            memory_types,
+            surface,
+            errors,
+        }
+    }
+
+    unsafe fn collect_surface(
+        loaders: &Loaders,
+        dev: vk::PhysicalDevice,
+        surface: vk::SurfaceKHR,
+    ) -> Result<SurfaceReport> {
+        let capabilities = loaders
+            .surface
+            .get_physical_device_surface_capabilities(dev, surface)
+            .context("Could not get surface capabilities")?;
+        let formats = loaders
+            .surface
+            .get_physical_device_surface_formats(dev, surface)
+            .context("Could not get surface formats")?;
+        let present_modes = loaders
+            .surface
+            .get_physical_device_surface_present_modes(dev, surface)
+            .context("Could not get present modes")?;
+
+        let extent = |e: vk::Extent2D| [e.width, e.height];
+
+        Ok(SurfaceReport {
+            min_image_count: capabilities.min_image_count,
+            max_image_count: capabilities.max_image_count,
+            current_extent: extent(capabilities.current_extent),
+            min_image_extent: extent(capabilities.min_image_extent),
+            max_image_extent: extent(capabilities.max_image_extent),
+            supported_usage: format!("{:?}", capabilities.supported_usage_flags),
+            supported_transforms: format!("{:?}", capabilities.supported_transforms),
+            supported_composite_alpha: format!("{:?}", capabilities.supported_composite_alpha),
+            formats: formats
+                .iter()
+                .map(|f| format!("{:?} / {:?}", f.format, f.color_space))
+                .collect(),
+            present_modes: present_modes.iter().map(|m| format!("{:?}", m)).collect(),
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Could not serialize Vulkan report")
+    }
+
+    pub fn summary(&self) -> String {
+        let mut s = String::new();
+        let inst = &self.instance;
+        let _ = writeln!(s, "Vulkan loader {}", inst.loader_api_version);
+        let _ = writeln!(
+            s,
+            "{} layers and {} instance extensions available",
+            inst.layers.len(),
+            inst.extensions.len()
+        );
+        let _ = writeln!(s, "Enabled layers: {}", inst.enabled_layers.join(", "));
+        let _ = writeln!(s, "Enabled extensions: {}", inst.enabled_extensions.join(", "));
+
+        for dev in self.devices.iter() {
+            let _ = writeln!(s);
+            let _ = writeln!(
+                s,
+                "Device {}: {} ({}), Vulkan {}, driver {:#x}, vendor {:#06x}, device {:#06x}",
+                dev.index,
+                dev.name,
+                dev.device_type,
+                dev.api_version,
+                dev.driver_version,
+                dev.vendor_id,
+                dev.device_id
+            );
+            let _ = writeln!(s, "  Pipeline cache UUID {}", dev.pipeline_cache_uuid);
+
+            let _ = writeln!(s, "  Queue families:");
+            for q in dev.queue_families.iter() {
+                let present = match q.supports_present {
+                    Some(true) => ", can present",
+                    Some(false) => ", cannot present",
+                    None => "",
+                };
+                let _ = writeln!(s, "    {}: {} x{}{}", q.index, q.flags, q.queue_count, present);
+            }
+
+            let _ = writeln!(s, "  Memory heaps:");
+            for h in dev.memory_heaps.iter() {
+                let _ = writeln!(s, "    {}: {} MiB {}", h.index, h.size >> 20, h.flags);
+            }
+            let _ = writeln!(s, "  Memory types:");
+            for t in dev.memory_types.iter() {
+                let _ = writeln!(s, "    {}: heap {} {}", t.index, t.heap_index, t.flags);
+            }
+
+            let _ = writeln!(s, "  Limits:");
+            for (name, value) in dev.limits.iter() {
+                let _ = writeln!(s, "    {} = {}", name, value);
+            }
+
+            let supported: Vec<&str> = dev
+                .features
+                .iter()
+                .filter(|&(_, &on)| on)
+                .map(|(&name, _)| name)
+                .collect();
+            let _ = writeln!(s, "  Features: {}", supported.join(", "));
+
+            let extension_names: Vec<&str> = dev.extensions.iter().map(|e| e.name.as_str()).collect();
+            let _ = writeln!(s, "  Extensions: {}", extension_names.join(", "));
+
+            if let Some(surf) = &dev.surface {
+                let _ = writeln!(
+                    s,
+                    "  Surface: {}..{} images, current {:?}, usage {}",
+                    surf.min_image_count, surf.max_image_count, surf.current_extent, surf.supported_usage
+                );
+                let _ = writeln!(s, "    Formats: {}", surf.formats.join(", "));
+                let _ = writeln!(s, "    Present modes: {}", surf.present_modes.join(", "));
+            }
+            for e in dev.errors.iter() {
+                let _ = writeln!(s, "  Error: {}", e);
+            }
+        }
+
+        s
+    }
+}
@@ -0,0 +1,331 @@
+use crate::barriers::*;
+use crate::config::*;
+use crate::debugmsg::*;
+use crate::device::*;
+use crate::handle::*;
+use crate::loaders::*;
+use crate::perframe::*;
+use crate::registry::*;
+use crate::renderer::*;
+use crate::swapsurface::*;
+use crate::upload::*;
+use crate::window::*;
+
+use anyhow::{bail, Context, Result};
+use ash::vk;
+use std::ffi::CStr;
+use std::rc::Rc;
+
+
+// A scripted run on headless surfaces that fails if the validation layers say anything at
+// warning level or above. Needs the Khronos validation layer and a driver with
+// VK_EXT_headless_surface, which lavapipe has, so it runs on a CI box with no display.
+
+// The device holds everything else alive, so it always goes last whatever the order.
+#[derive(Clone, Copy, Debug)]
+enum DropOrder {
+    WindowsFirst,
+    RendererFirst,
+}
+
+const FRAMES_PER_STEP: usize = 2 * FRAMES_IN_FLIGHT;
+const WINDOW_COUNT: u32 = 2;
+
+// The machine is missing something the self test needs. Not a failure.
+#[derive(Debug)]
+pub struct Unsupported(pub String);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+pub unsafe fn run() -> Result<()> {
+    for order in [DropOrder::WindowsFirst, DropOrder::RendererFirst] {
+        log::info!("Self test with drop order {:?}", order);
+
+        let config = InstanceConfig::default().apply_env().validation(true).track_host_memory(true);
+        let loaders = match Loaders::new_headless(config) {
+            Ok(loaders) => Rc::new(loaders),
+            Err(e) => return Err(Unsupported(format!("Could not create a headless Vulkan instance: {:?}", e)).into()),
+        };
+
+        let validation_layer = CStr::from_bytes_with_nul(b"VK_LAYER_KHRONOS_validation\0").unwrap();
+        if !loaders.has_layer(validation_layer) {
+            return Err(Unsupported("The self test needs the Khronos validation layer to be installed".to_string()).into());
+        }
+        if loaders.headless_surface.is_none() {
+            return Err(Unsupported("The self test needs a driver with VK_EXT_headless_surface, such as lavapipe".to_string()).into());
+        }
+
+        let capture = MessageCapture::install(loaders.clone())?;
+        let result = scenario(loaders.clone(), order);
+        let messages = capture.messages();
+        drop(capture);
+
+        result.with_context(|| format!("Self test failed with drop order {:?}", order))?;
+
+        // Destroying the instance is reported through the Loaders' own messenger, which the
+        // capture can't outlive.
+        if Rc::strong_count(&loaders) > 1 {
+            bail!("Something still holds the Loaders after the scenario with drop order {:?}", order);
+        }
+        let counts = loaders.debug_counts.clone();
+        let reported_before = counts.errors() + counts.warnings();
+        drop(loaders);
+        let teardown_reports = counts.errors() + counts.warnings() - reported_before;
+        if teardown_reports > 0 {
+            bail!(
+                "Validation reported {} warnings or errors while destroying the instance with drop order {:?}",
+                teardown_reports,
+                order
+            );
+        }
+
+        if !messages.is_empty() {
+            let text: Vec<String> = messages
+                .iter()
+                .map(|m| format!("{:?}: {}", m.severity, m.text))
+                .collect();
+            bail!(
+                "Validation reported {} messages with drop order {:?}:\n{}",
+                messages.len(),
+                order,
+                text.join("\n")
+            );
+        }
+    }
+
+    println!("Self test passed, no validation warnings or errors");
+    Ok(())
+}
+
+unsafe fn scenario(loaders: Rc<Loaders>, order: DropOrder) -> Result<()> {
+    let alive_before = objects_alive();
+    let surfaces = (0..WINDOW_COUNT)
+        .map(|_| Surface::headless(loaders.clone()).map(Rc::new))
+        .collect::<Result<Vec<Rc<Surface>>>>()?;
+
+    // The first run records inline, tracks submissions with timeline semaphores and records
+    // synchronization2 barriers. The second uses worker threads, fences and legacy barriers.
+    let first = matches!(order, DropOrder::WindowsFirst);
+    // Kept in memory, so the self test neither trusts nor overwrites the user's cache.
+    let config = DeviceConfig::default()
+        .apply_env()
+        .pipeline_cache(None)
+        .recording_threads(if first { 0 } else { 2 })
+        .timeline_semaphores(first)
+        .synchronization2(first);
+    let device = Rc::new(
+        Device::create(loaders.clone(), surfaces[0].surface, config).context("Could not create Vulkan Device")?,
+    );
+
+    let mut renderer: Option<Renderer> = None;
+    let mut windows = Vec::<VulkanWindow>::new();
+    for (i, surface) in surfaces.into_iter().enumerate() {
+        let label = format!("selftest window {}", i);
+        let extent = vk::Extent2D {
+            width: 320 + 64 * i as u32,
+            height: 240,
+        };
+
+        let mut swap = PerSwapchain::new(device.clone(), extent, surface.clone(), renderer.as_ref(), None, &label)
+            .context("Could not create swapchain")?;
+        if renderer.is_none() {
+            let r = Renderer::new(device.clone(), &swap).context("Could not create Renderer")?;
+            swap.create_framebuffers(&r).context("Could not create framebuffers")?;
+            renderer = Some(r);
+        }
+
+        let mut window = VulkanWindow::new(label, None, extent, surface, swap)?;
+        window.shape_rotate_speed = 1.0;
+        window.color_rotate_speed = -0.5;
+        windows.push(window);
+    }
+    let mut renderer = renderer.unwrap();
+
+    render_frames(&mut renderer, &mut windows)?;
+
+    // Nothing samples them, but the upload's layout transitions go through the barrier helper,
+    // and then a frame streams new contents into both through its staging buffer.
+    let extent = vk::Extent3D {
+        width: 4,
+        height: 4,
+        depth: 1,
+    };
+    let texels = [0x80u8; 4 * 4 * 4];
+    let (texture, ticket) = Image::device_local(
+        &device,
+        &vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(vk::Format::R8G8B8A8_UNORM)
+            .extent(extent)
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::SAMPLED),
+        &texels,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        "selftest texture",
+    )
+    .context("Could not upload a texture")?;
+    device.wait_upload(ticket)?;
+    let vertices = [0u8; 256];
+    let (vertex_buffer, ticket) =
+        Buffer::device_local(&device, vk::BufferUsageFlags::VERTEX_BUFFER, &vertices, "selftest vertices")
+            .context("Could not upload a buffer")?;
+    device.wait_upload(ticket)?;
+    stream_through_frame(&device, &mut windows[0].per_frame[0], &texture, extent, &vertex_buffer)
+        .context("Could not stream through a frame's staging buffer")?;
+    drop(texture);
+    drop(vertex_buffer);
+
+    // Resize every window, which goes through the same swapchain recreation path as a real
+    // window being dragged.
+    for w in windows.iter_mut() {
+        w.headless_extent = vk::Extent2D {
+            width: w.headless_extent.width / 2 + 16,
+            height: w.headless_extent.height * 2,
+        };
+        w.recreate_swapchain(&renderer)?;
+    }
+
+    render_frames(&mut renderer, &mut windows)?;
+
+    // Pretend the driver lost one surface and then the whole device. Both go through the same
+    // recovery as the main loop, and rendering has to carry on afterwards.
+    let frame = windows[0].frame_count;
+    windows[0].injected_fault = Some((frame, vk::Result::ERROR_SURFACE_LOST_KHR));
+    render_frames(&mut renderer, &mut windows)?;
+
+    let anim_start_time = windows[1].anim_start_time;
+    let frame = windows[1].frame_count;
+    windows[1].injected_fault = Some((frame, vk::Result::ERROR_DEVICE_LOST));
+    render_frames(&mut renderer, &mut windows)?;
+    if Rc::ptr_eq(&renderer.device, &device) {
+        bail!("The device was not replaced after an injected device loss");
+    }
+    if windows[1].anim_start_time != anim_start_time {
+        bail!("Animation state was not kept across device loss recovery");
+    }
+    drop(device);
+    let device = renderer.device.clone();
+
+    // Close one window while the other keeps going. Its objects are only deferred, and
+    // rendering the other window has to get them destroyed.
+    let alive = device.objects_alive();
+    windows.remove(0);
+    if device.deferred_count() == 0 {
+        bail!("Closing a window destroyed its objects straight away instead of deferring them");
+    }
+    render_frames(&mut renderer, &mut windows)?;
+    if device.deferred_count() > 0 {
+        bail!("{} objects from the closed window were never destroyed", device.deferred_count());
+    }
+    if cfg!(debug_assertions) && device.objects_alive() >= alive {
+        bail!("The live object registry still has everything from the closed window");
+    }
+
+    match order {
+        DropOrder::WindowsFirst => {
+            drop(windows);
+            drop(renderer);
+            drop(device);
+        }
+        DropOrder::RendererFirst => {
+            drop(renderer);
+            drop(windows);
+            drop(device);
+        }
+    }
+
+    // Only counts in debug builds. A leak from a device that was dropped is logged instead.
+    if objects_alive() > alive_before {
+        bail!("{} Vulkan objects were never destroyed", objects_alive() - alive_before);
+    }
+
+    Ok(())
+}
+
+// Records new contents for texture and buffer into the frame's command buffer, the way a frame
+// updates its dynamic data, and waits for them to land.
+unsafe fn stream_through_frame(
+    device: &Device,
+    pf: &mut PerFrame,
+    texture: &Image,
+    extent: vk::Extent3D,
+    buffer: &Buffer,
+) -> Result<()> {
+    let dev = &device.device;
+    device.wait_submission(pf.submission, u64::MAX)?;
+    pf.reset()?;
+    let command_buffer = pf.command_buffer;
+    dev.begin_command_buffer(command_buffer, &vk::CommandBufferBeginInfo::default())?;
+
+    let mut tracker = LayoutTracker::new();
+    tracker.track(texture.handle(), vk::ImageAspectFlags::COLOR, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+    tracker.use_image(texture.handle(), ImageUse::TransferDst);
+    tracker.flush(device, command_buffer);
+    let texels = [0xffu8; 4 * 4 * 4];
+    pf.staging
+        .copy_to_image(
+            command_buffer,
+            texture.handle(),
+            vk::Format::R8G8B8A8_UNORM,
+            vk::ImageAspectFlags::COLOR,
+            extent,
+            &texels,
+        )?;
+    let vertices = [0x3fu8; 256];
+    pf.staging.copy_to_buffer(command_buffer, buffer.handle(), 0, &vertices)?;
+    pf.staging.barrier(
+        command_buffer,
+        vk::PipelineStageFlags::VERTEX_INPUT,
+        vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+    );
+    tracker.use_image(texture.handle(), ImageUse::ShaderRead);
+    tracker.flush(device, command_buffer);
+    dev.end_command_buffer(command_buffer)?;
+
+    let fence = pf.in_flight_fence.as_ref().map_or(vk::Fence::null(), |f| f.handle());
+    if fence != vk::Fence::null() {
+        dev.reset_fences(&[fence])?;
+    }
+    pf.submission = device.submit(device.graphics_queue, &[command_buffer], &[], &[], fence)?;
+    device.wait_submission(pf.submission, u64::MAX)?;
+    Ok(())
+}
+
+unsafe fn render_frames(renderer: &mut Renderer, windows: &mut [VulkanWindow]) -> Result<()> {
+    for _ in 0..FRAMES_PER_STEP {
+        let mut device_lost = false;
+        for w in windows.iter_mut() {
+            match renderer.render(w) {
+                Ok(_) => {}
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                    w.recreate_swapchain(renderer)?;
+                }
+                Err(vk::Result::ERROR_SURFACE_LOST_KHR) => {
+                    w.recreate_surface(renderer)?;
+                }
+                Err(vk::Result::ERROR_DEVICE_LOST) => {
+                    device_lost = true;
+                    break;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Rendering {}", w.label));
+                }
+            }
+        }
+
+        if device_lost {
+            let mut windows: Vec<&mut VulkanWindow> = windows.iter_mut().collect();
+            renderer.recover_device_lost(&mut windows)?;
+        }
+    }
+    Ok(())
+}
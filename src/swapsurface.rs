@@ -1,11 +1,12 @@
 use crate::loaders::*;
 use crate::device::*;
 use crate::renderer::*;
+use crate::handle::*;
+use crate::registry::*;
 
 use anyhow::{Context, Result};
 use winit::window::Window;
 
-use ash::prelude::*;
 use ash::vk::{self, SwapchainKHR};
 use std::cmp;
 use std::default::Default;
@@ -17,10 +18,40 @@ pub struct Surface {
     pub surface: vk::SurfaceKHR,
 }
 
+impl Surface {
+    pub unsafe fn for_window(loaders: Rc<Loaders>, window: &Window) -> Result<Surface> {
+        let surface = ash_window::create_surface(&loaders.entry, &loaders.instance, window, loaders.allocation_callbacks())
+            .context("Could not create surface from window handle")?;
+        register_object(&*loaders, surface);
+        Ok(Surface { loaders, surface })
+    }
+
+    // A surface that never reaches a display, for running swapchain code on CI.
+    pub unsafe fn headless(loaders: Rc<Loaders>) -> Result<Surface> {
+        let surface = loaders
+            .headless_surface
+            .as_ref()
+            .context("VK_EXT_headless_surface is not available")?
+            .create_headless_surface(&vk::HeadlessSurfaceCreateInfoEXT::default(), loaders.allocation_callbacks())
+            .context("Could not create headless surface")?;
+        register_object(&*loaders, surface);
+        Ok(Surface { loaders, surface })
+    }
+
+    // Stands in for a surface that's been given up, so it can be destroyed before the
+    // replacement is made.
+    pub fn none(loaders: Rc<Loaders>) -> Surface {
+        Surface {
+            loaders,
+            surface: vk::SurfaceKHR::null(),
+        }
+    }
+}
+
 impl Drop for Surface {
     fn drop(&mut self) {
-        unsafe {
-            self.loaders.surface.destroy_surface(self.surface, None);
+        if self.surface != vk::SurfaceKHR::null() {
+            unsafe { self.surface.destroy(&self.loaders) };
         }
     }
 }
@@ -30,21 +61,31 @@ pub struct PerSwapchain {
     pub surface: Rc<Surface>,
     pub swapchain: vk::SwapchainKHR,
     pub images: Vec<vk::Image>,
-    pub views: Vec<vk::ImageView>,
-    pub framebuffers: Vec<vk::Framebuffer>,
+    pub views: Vec<ImageView>,
+    pub framebuffers: Vec<Framebuffer>,
     pub size: vk::Extent2D,
     pub format: vk::SurfaceFormatKHR,
+    pub label: String,
+    // Not necessarily the device's present queue, since every window has its own surface.
+    pub present_queue_family: u32,
+    pub present_queue: vk::Queue,
 }
 
 impl PerSwapchain {
     pub fn new(
         device: Rc<Device>,
-        window: &Window,
+        window_size: vk::Extent2D,
         surface: Rc<Surface>,
         renderer: Option<&Renderer>,
         old: Option<&PerSwapchain>,
+        label: &str,
     ) -> Result<PerSwapchain> {
+        let _owner = OwnerScope::enter(label);
         unsafe {
+            let (present_queue_family, present_queue) = device
+                .present_queue_for(surface.surface)
+                .with_context(|| format!("{} can't be presented on this device", label))?;
+
             let capabilities = device
                 .loaders
                 .surface
@@ -65,7 +106,6 @@ impl PerSwapchain {
 
             let image_count = capabilities.min_image_count;
 
-            let window_size = window.inner_size();
             let swap_size = vk::Extent2D {
                 width: cmp::min(
                     cmp::max(window_size.width, capabilities.min_image_extent.width),
@@ -124,7 +164,7 @@ impl PerSwapchain {
                     None => SwapchainKHR::default(),
                 });
 
-            let shared_queues = [device.present_queue_family, device.graphics_queue_family];
+            let shared_queues = [present_queue_family, device.graphics_queue_family];
             let swapchain_info = if shared_queues[0] != shared_queues[1] {
                 swapchain_info
                     .image_sharing_mode(vk::SharingMode::CONCURRENT)
@@ -135,45 +175,43 @@ impl PerSwapchain {
 
             let swapchain = device
                 .swapchain_loader
-                .create_swapchain(&swapchain_info, None)
+                .create_swapchain(&swapchain_info, device.allocation_callbacks())
                 .context("Could not create swapchain")?;
+            register_object(&*device, swapchain);
 
-            let images = device
-                .swapchain_loader
-                .get_swapchain_images(swapchain)
-                .context("Could not get images for swapchain")?;
-
-            let views = images
-                .iter()
-                .map(|&image| {
-                    let subresource_info = vk::ImageSubresourceRange::default()
-                        .aspect_mask(vk::ImageAspectFlags::COLOR)
-                        .base_mip_level(0)
-                        .level_count(1)
-                        .base_array_layer(0)
-                        .layer_count(1);
-
-                    let view_info = vk::ImageViewCreateInfo::default()
-                        .image(image)
-                        .view_type(vk::ImageViewType::TYPE_2D)
-                        .format(format.format)
-                        .components(vk::ComponentMapping::default())
-                        .subresource_range(subresource_info);
-
-                    device.device.create_image_view(&view_info, None)
-                })
-                .collect::<VkResult<Vec<vk::ImageView>>>()?;
-
+            // Built straight away so anything failing below still destroys the swapchain.
             let mut result = PerSwapchain {
                 device,
                 surface,
                 swapchain,
-                images,
-                views,
+                images: Vec::new(),
+                views: Vec::new(),
                 framebuffers: Vec::new(),
                 size: swap_size,
                 format,
+                label: label.to_string(),
+                present_queue_family,
+                present_queue,
             };
+            let device = &result.device;
+            device.set_name(swapchain, &format!("{} swapchain", label));
+
+            result.images = device
+                .swapchain_loader
+                .get_swapchain_images(swapchain)
+                .context("Could not get images for swapchain")?;
+
+            for (i, &image) in result.images.iter().enumerate() {
+                device.set_name(image, &format!("{} swapchain image {}", label, i));
+                let view = ImageView::new_2d(
+                    device,
+                    image,
+                    format.format,
+                    vk::ImageAspectFlags::COLOR,
+                    &format!("{} swapchain view {}", label, i),
+                )?;
+                result.views.push(view);
+            }
 
             if let Some(r) = renderer {
                 result
@@ -185,52 +223,72 @@ impl PerSwapchain {
         }
     }
 
-    pub fn create_framebuffers(&mut self, renderer: &Renderer) -> VkResult<()> {
+    // Destroys the Vulkan objects now and leaves an empty PerSwapchain that drops as a no-op.
+    // After a lost device the swapchain can't be passed as oldSwapchain, and the window only
+    // takes a new swapchain once this one is gone. submissions are the ones that used it, which
+    // are waited for first; other windows' work carries on. Swapchains retired before this one
+    // are already out of the window's way, and go through the deletion queue as usual.
+    pub fn release(&mut self, submissions: &[u64]) {
+        unsafe {
+            for &value in submissions {
+                // A lost device won't finish them, but has nothing left running either.
+                let _ = self.device.wait_submission(value, u64::MAX);
+            }
+            for framebuffer in self.framebuffers.drain(..) {
+                framebuffer.framebuffer.into_raw().destroy(&*self.device);
+            }
+            for view in self.views.drain(..) {
+                view.view.into_raw().destroy(&*self.device);
+            }
+            self.images.clear();
+            if self.swapchain != vk::SwapchainKHR::null() {
+                self.swapchain.destroy(&self.device);
+                self.swapchain = vk::SwapchainKHR::null();
+            }
+            self.device.collect_garbage();
+        }
+    }
+
+    pub fn create_framebuffers(&mut self, renderer: &Renderer) -> Result<()> {
         assert!(self.framebuffers.is_empty());
         assert!(!self.images.is_empty());
         assert_eq!(self.device.device.handle(), renderer.device.device.handle());
+        let _owner = OwnerScope::enter(&self.label);
 
-        unsafe {
-            for &image_view in self.views.iter() {
-                match self.device.device.create_framebuffer(
-                    &vk::FramebufferCreateInfo::default()
-                        .render_pass(renderer.renderpass)
-                        .attachments(&[image_view])
-                        .width(self.size.width)
-                        .height(self.size.height)
-                        .layers(1),
-                    None,
-                ) {
-                    Ok(fb) => {
-                        self.framebuffers.push(fb);
-                    }
-                    Err(e) => {
-                        for fb in self.framebuffers.iter() {
-                            self.device.device.destroy_framebuffer(*fb, None);
-                        }
-                        return Err(e);
-                    }
-                }
-            }
-        }
+        // Collected first so a failure part way drops the ones already made.
+        let framebuffers = self
+            .views
+            .iter()
+            .enumerate()
+            .map(|(i, view)| unsafe {
+                Framebuffer::new(
+                    &self.device,
+                    *renderer.renderpass,
+                    &[view.handle()],
+                    self.size,
+                    &format!("{} framebuffer {}", self.label, i),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.framebuffers = framebuffers;
 
         Ok(())
     }
 }
 
+// Doesn't wait for anything; it all goes through the deletion queue. The swapchain is retired
+// after its views and framebuffers, and takes the surface with it so that goes last.
 impl Drop for PerSwapchain {
     fn drop(&mut self) {
-        unsafe {
-            let _ = self.device.device.device_wait_idle();
-            for fb in self.framebuffers.iter() {
-                self.device.device.destroy_framebuffer(*fb, None);
-            }
-            for &view in self.views.iter() {
-                self.device.device.destroy_image_view(view, None);
-            }
-            self.device
-                .swapchain_loader
-                .destroy_swapchain(self.swapchain, None);
+        self.framebuffers.clear();
+        self.views.clear();
+        if self.swapchain != vk::SwapchainKHR::null() {
+            let swapchain = self.swapchain;
+            let surface = self.surface.clone();
+            self.device.defer(move |device| unsafe {
+                swapchain.destroy(device);
+                drop(surface);
+            });
         }
     }
 }
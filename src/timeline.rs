@@ -0,0 +1,182 @@
+use crate::device::*;
+use crate::registry::*;
+
+use ash::prelude::*;
+use ash::vk;
+
+
+// When the GPU is done with what. Every submit gets the next value of one device-wide counter,
+// and anything that needs to know whether work has finished holds on to that value: frame
+// pacing, deferred destruction and upload batches all do. With timeline semaphores each queue
+// has one that its submits signal with their value, so checking a value is a counter query.
+// Without them a submit signals a fence, which is kept until it's been seen to signal. The
+// values submitted to any one queue only go up, which is all a timeline semaphore needs.
+
+#[derive(Clone, Copy)]
+enum Signal {
+    Timeline(vk::Semaphore),
+    Fence(vk::Fence),
+}
+
+pub struct GpuTimeline {
+    // One per queue we created, or none when falling back to fences.
+    semaphores: Vec<(vk::Queue, vk::Semaphore)>,
+    last_value: u64,
+    // Submissions that might still be running.
+    pending: Vec<(u64, Signal)>,
+}
+
+impl GpuTimeline {
+    pub fn new() -> Self {
+        GpuTimeline {
+            semaphores: Vec::new(),
+            last_value: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    fn semaphore(&self, queue: vk::Queue) -> Option<vk::Semaphore> {
+        self.semaphores.iter().find(|&&(q, _)| q == queue).map(|&(_, s)| s)
+    }
+
+    // Called from Device::drop once the device is idle.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.pending.clear();
+        for (_, semaphore) in self.semaphores.drain(..) {
+            unregister_object(device, semaphore);
+            device.device.destroy_semaphore(semaphore, device.allocation_callbacks());
+        }
+    }
+}
+
+impl Device {
+    // Gives every queue a timeline semaphore. Only for devices with the timeline_semaphore
+    // feature enabled, before anything has been submitted.
+    pub unsafe fn create_timelines(&self) -> VkResult<()> {
+        for (_, queue) in self.queues() {
+            let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+                .semaphore_type(vk::SemaphoreType::TIMELINE)
+                .initial_value(0);
+            let semaphore = self.device.create_semaphore(
+                &vk::SemaphoreCreateInfo::default().push_next(&mut type_info),
+                self.allocation_callbacks(),
+            )?;
+            register_object(self, semaphore);
+            self.set_name(semaphore, "queue timeline");
+            self.timeline.borrow_mut().semaphores.push((queue, semaphore));
+        }
+        Ok(())
+    }
+
+    pub fn has_timelines(&self) -> bool {
+        !self.timeline.borrow().semaphores.is_empty()
+    }
+
+    // Submits command buffers to queue and returns the submission's value. The wait semaphores
+    // and signal semaphores are binary ones. fence is signalled too if it isn't null, and is
+    // what tells us the submission is done when there are no timelines, so it's required then
+    // and mustn't be reset before the submission is known to be done.
+    pub unsafe fn submit(
+        &self,
+        queue: vk::Queue,
+        command_buffers: &[vk::CommandBuffer],
+        wait: &[(vk::Semaphore, vk::PipelineStageFlags)],
+        signal: &[vk::Semaphore],
+        fence: vk::Fence,
+    ) -> VkResult<u64> {
+        let value = self.timeline.borrow().last_value + 1;
+        let timeline = self.timeline.borrow().semaphore(queue);
+        debug_assert!(timeline.is_some() || fence != vk::Fence::null(), "Submitting with nothing to track it by");
+
+        let (wait_semaphores, wait_stages): (Vec<vk::Semaphore>, Vec<vk::PipelineStageFlags>) =
+            wait.iter().copied().unzip();
+        let wait_values = vec![0; wait.len()];
+        let mut signal_semaphores = signal.to_vec();
+        let mut signal_values = vec![0; signal.len()];
+        if let Some(semaphore) = timeline {
+            signal_semaphores.push(semaphore);
+            signal_values.push(value);
+        }
+
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::default()
+            .wait_semaphore_values(&wait_values)
+            .signal_semaphore_values(&signal_values);
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(command_buffers)
+            .signal_semaphores(&signal_semaphores);
+        let submit_info = if timeline.is_some() {
+            submit_info.push_next(&mut timeline_info)
+        } else {
+            submit_info
+        };
+        self.device.queue_submit(queue, &[submit_info], fence)?;
+
+        let mut gpu_timeline = self.timeline.borrow_mut();
+        gpu_timeline.last_value = value;
+        let signal = match timeline {
+            Some(semaphore) => Signal::Timeline(semaphore),
+            None => Signal::Fence(fence),
+        };
+        gpu_timeline.pending.push((value, signal));
+        Ok(value)
+    }
+
+    // The value of the latest submit, or 0 before the first.
+    pub fn last_submission(&self) -> u64 {
+        self.timeline.borrow().last_value
+    }
+
+    // Checks every submission nobody is waiting on, and forgets the ones that have finished.
+    pub fn poll_submissions(&self) {
+        let mut timeline = self.timeline.borrow_mut();
+        // A lost device won't be signalling anything, so nothing is running any more.
+        let counters: Vec<(vk::Semaphore, u64)> = timeline
+            .semaphores
+            .iter()
+            .map(|&(_, s)| (s, unsafe { self.device.get_semaphore_counter_value(s) }.unwrap_or(u64::MAX)))
+            .collect();
+        timeline.pending.retain(|&(value, signal)| match signal {
+            Signal::Timeline(semaphore) => counters.iter().any(|&(s, counter)| s == semaphore && counter < value),
+            Signal::Fence(fence) => !unsafe { self.device.get_fence_status(fence) }.unwrap_or(true),
+        });
+    }
+
+    pub fn submission_done(&self, value: u64) -> bool {
+        self.poll_submissions();
+        let timeline = self.timeline.borrow();
+        value <= timeline.last_value && !timeline.pending.iter().any(|&(v, _)| v == value)
+    }
+
+    // Everything with a value below this is done.
+    pub fn oldest_pending_submission(&self) -> u64 {
+        let timeline = self.timeline.borrow();
+        timeline.pending.iter().map(|&(v, _)| v).min().unwrap_or(timeline.last_value + 1)
+    }
+
+    // Blocks until the submission is done, or for at most timeout nanoseconds. Returns straight
+    // away for one that's already known to be done, and for 0.
+    pub unsafe fn wait_submission(&self, value: u64, timeout: u64) -> VkResult<()> {
+        let signal = match self.timeline.borrow().pending.iter().find(|&&(v, _)| v == value) {
+            Some(&(_, signal)) => signal,
+            None => return Ok(()),
+        };
+        match signal {
+            Signal::Timeline(semaphore) => self.device.wait_semaphores(
+                &vk::SemaphoreWaitInfo::default()
+                    .semaphores(&[semaphore])
+                    .values(&[value]),
+                timeout,
+            )?,
+            Signal::Fence(fence) => self.device.wait_for_fences(&[fence], true, timeout)?,
+        }
+        self.timeline.borrow_mut().pending.retain(|&(v, _)| v != value);
+        Ok(())
+    }
+
+    // Only once the device is idle.
+    pub fn forget_submissions(&self) {
+        self.timeline.borrow_mut().pending.clear();
+    }
+}
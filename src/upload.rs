@@ -0,0 +1,629 @@
+use crate::barriers::*;
+use crate::device::*;
+use crate::handle::*;
+use crate::memory::*;
+use crate::registry::*;
+
+use anyhow::{bail, Context, Result};
+use ash::vk::{self, Handle};
+use std::rc::Rc;
+
+
+// Gets data into DEVICE_LOCAL memory through host visible staging buffers.
+//
+// One-shot uploads are recorded into a batch on the transfer queue and go in a single submit,
+// either from flush_uploads() or once the batch holds too much staging memory. Each returns a
+// ticket that can be polled or waited on; the staging memory is freed once its batch is done.
+// Lives in a RefCell on Device, like the allocator.
+//
+// Streaming uploads that change every frame go through a StagingBuffer instead, recorded into
+// the frame's own command buffer, so they need no extra submit and no cross-queue sync.
+
+// A batch with this much staging memory is submitted before more is added to it.
+const MAX_BATCH_BYTES: vk::DeviceSize = 32 << 20;
+
+// Where buffer copies out of a StagingBuffer start. Image copies work out their own alignment
+// from the format, see copy_block_size().
+const STAGING_ALIGNMENT: vk::DeviceSize = 16;
+
+// Uploads are done once the batch with this serial has finished on the GPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UploadTicket(pub u64);
+
+struct Batch {
+    serial: u64,
+    // The value of its submit, once it's been submitted.
+    submission: u64,
+    command_buffer: vk::CommandBuffer,
+    // Null when the device has timelines.
+    fence: vk::Fence,
+    staging: Vec<(vk::Buffer, Allocation)>,
+    staged_bytes: vk::DeviceSize,
+}
+
+pub struct UploadContext {
+    recording: Option<Batch>,
+    in_flight: Vec<Batch>,
+    // Command buffers and unsignalled fences, if any, from finished batches, ready to be used
+    // again.
+    spare: Vec<(vk::CommandBuffer, vk::Fence)>,
+    next_serial: u64,
+}
+
+impl UploadContext {
+    pub fn new() -> Self {
+        UploadContext {
+            recording: None,
+            in_flight: Vec::new(),
+            spare: Vec::new(),
+            next_serial: 1,
+        }
+    }
+
+    // Counts the batch still being recorded, so its tickets aren't reported as done.
+    fn oldest_pending(&self) -> u64 {
+        self.in_flight
+            .iter()
+            .chain(self.recording.iter())
+            .map(|b| b.serial)
+            .min()
+            .unwrap_or(self.next_serial)
+    }
+
+    unsafe fn batch(&mut self, device: &Device) -> Result<&mut Batch> {
+        if self.recording.is_none() {
+            let (command_buffer, fence) = match self.spare.pop() {
+                Some(spare) => spare,
+                None => {
+                    let fence = if device.has_timelines() {
+                        vk::Fence::null()
+                    } else {
+                        let fence = device
+                            .device
+                            .create_fence(&vk::FenceCreateInfo::default(), device.allocation_callbacks())
+                            .context("Could not create upload fence")?;
+                        register_object(device, fence);
+                        device.set_name(fence, "upload fence");
+                        fence
+                    };
+                    let command_buffer = match device.device.allocate_command_buffers(
+                        &vk::CommandBufferAllocateInfo::default()
+                            .command_pool(device.transfer_command_pool)
+                            .level(vk::CommandBufferLevel::PRIMARY)
+                            .command_buffer_count(1),
+                    ) {
+                        Ok(buffers) => buffers[0],
+                        Err(e) => {
+                            if fence != vk::Fence::null() {
+                                unregister_object(device, fence);
+                                device.device.destroy_fence(fence, device.allocation_callbacks());
+                            }
+                            return Err(e).context("Could not allocate upload command buffer");
+                        }
+                    };
+                    register_pooled(device, device.transfer_command_pool.as_raw(), command_buffer);
+                    device.set_name(command_buffer, "upload command buffer");
+                    (command_buffer, fence)
+                }
+            };
+
+            if let Err(e) = device.device.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::default().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            ) {
+                self.spare.push((command_buffer, fence));
+                return Err(e).context("Could not begin upload command buffer");
+            }
+
+            self.recording = Some(Batch {
+                serial: self.next_serial,
+                submission: 0,
+                command_buffer,
+                fence,
+                staging: Vec::new(),
+                staged_bytes: 0,
+            });
+            self.next_serial += 1;
+        }
+        Ok(self.recording.as_mut().unwrap())
+    }
+
+    // Only for batches the GPU is finished with, or that never reached it.
+    unsafe fn recycle(&mut self, device: &Device, batch: Batch) {
+        for (buffer, allocation) in batch.staging {
+            unregister_object(device, buffer);
+            device.device.destroy_buffer(buffer, device.allocation_callbacks());
+            device.free(allocation);
+        }
+        let _ = device
+            .device
+            .reset_command_buffer(batch.command_buffer, vk::CommandBufferResetFlags::empty());
+        self.spare.push((batch.command_buffer, batch.fence));
+    }
+
+    // Called from Device::drop before the allocator goes. The command buffers go with the pool.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for batch in self.in_flight.iter() {
+            let _ = device.wait_submission(batch.submission, u64::MAX);
+        }
+        let batches: Vec<Batch> = self.recording.take().into_iter().chain(self.in_flight.drain(..)).collect();
+        for batch in batches {
+            self.recycle(device, batch);
+        }
+        for (_, fence) in self.spare.drain(..) {
+            if fence != vk::Fence::null() {
+                unregister_object(device, fence);
+                device.device.destroy_fence(fence, device.allocation_callbacks());
+            }
+        }
+    }
+}
+
+impl Device {
+    // The families a resource has to be shared between to be filled on the transfer queue and
+    // used on the graphics queue. Empty when they're the same, so EXCLUSIVE is fine.
+    pub fn upload_queue_families(&self) -> Vec<u32> {
+        if self.has_dedicated_transfer() {
+            vec![self.graphics_queue_family, self.transfer_queue_family]
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Copies bytes into a new staging buffer owned by the current batch, and returns it along
+    // with the command buffer to record the copy out of it into.
+    unsafe fn stage(&self, bytes: &[u8]) -> Result<(vk::Buffer, UploadTicket, vk::CommandBuffer)> {
+        if bytes.is_empty() {
+            bail!("Nothing to upload");
+        }
+        let size = bytes.len() as vk::DeviceSize;
+
+        let full = self
+            .uploads
+            .borrow()
+            .recording
+            .as_ref()
+            .map_or(false, |b| b.staged_bytes > 0 && b.staged_bytes + size > MAX_BATCH_BYTES);
+        if full {
+            self.flush_uploads()?;
+        }
+
+        let buffer = self
+            .device
+            .create_buffer(
+                &vk::BufferCreateInfo::default()
+                    .size(size)
+                    .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                self.allocation_callbacks(),
+            )
+            .context("Could not create staging buffer")?;
+        register_object(self, buffer);
+        let staged = self
+            .allocate_for_buffer(buffer, MemoryUsage::Upload)
+            .and_then(|allocation| match self.write_memory(&allocation, 0, bytes) {
+                Ok(()) => Ok(allocation),
+                Err(e) => {
+                    self.free(allocation);
+                    Err(e)
+                }
+            });
+        let allocation = match staged {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                unregister_object(self, buffer);
+                self.device.destroy_buffer(buffer, self.allocation_callbacks());
+                return Err(e).context("Could not fill staging buffer");
+            }
+        };
+
+        let mut uploads = self.uploads.borrow_mut();
+        let batch = match uploads.batch(self) {
+            Ok(batch) => batch,
+            Err(e) => {
+                unregister_object(self, buffer);
+                self.device.destroy_buffer(buffer, self.allocation_callbacks());
+                self.free(allocation);
+                return Err(e);
+            }
+        };
+        batch.staging.push((buffer, allocation));
+        batch.staged_bytes += size;
+        Ok((buffer, UploadTicket(batch.serial), batch.command_buffer))
+    }
+
+    pub unsafe fn upload_buffer(&self, dst: vk::Buffer, dst_offset: vk::DeviceSize, bytes: &[u8]) -> Result<UploadTicket> {
+        let (staging, ticket, command_buffer) = self.stage(bytes)?;
+        self.device.cmd_copy_buffer(
+            command_buffer,
+            staging,
+            dst,
+            &[vk::BufferCopy {
+                src_offset: 0,
+                dst_offset,
+                size: bytes.len() as vk::DeviceSize,
+            }],
+        );
+        Ok(ticket)
+    }
+
+    // Fills mip 0, layer 0 of the image from tightly packed texels. The old contents are
+    // discarded and the whole image is left in final_layout.
+    pub unsafe fn upload_image(
+        &self,
+        dst: vk::Image,
+        aspect: vk::ImageAspectFlags,
+        extent: vk::Extent3D,
+        bytes: &[u8],
+        final_layout: vk::ImageLayout,
+    ) -> Result<UploadTicket> {
+        let (staging, ticket, command_buffer) = self.stage(bytes)?;
+
+        let mut barriers = LayoutTracker::new();
+        barriers.track(dst, aspect, vk::ImageLayout::UNDEFINED);
+        barriers.use_image(dst, ImageUse::TransferDst);
+        barriers.flush(self, command_buffer);
+        self.device.cmd_copy_buffer_to_image(
+            command_buffer,
+            staging,
+            dst,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: aspect,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: extent,
+            }],
+        );
+        // The transfer queue may not know the graphics stages, and the ticket orders the rest.
+        barriers.use_image(dst, ImageUse::Layout(final_layout));
+        barriers.flush(self, command_buffer);
+        Ok(ticket)
+    }
+
+    // Submits the batch being recorded. Returns its ticket, or that of the last batch if there
+    // was nothing to submit.
+    pub unsafe fn flush_uploads(&self) -> Result<UploadTicket> {
+        let mut uploads = self.uploads.borrow_mut();
+        let mut batch = match uploads.recording.take() {
+            Some(batch) => batch,
+            None => return Ok(UploadTicket(uploads.next_serial - 1)),
+        };
+
+        let serial = batch.serial;
+        let submitted = self.device.end_command_buffer(batch.command_buffer).and_then(|_| {
+            self.queue_begin_label(self.transfer_queue, format_args!("upload batch {}", serial));
+            let submitted = self.submit(self.transfer_queue, &[batch.command_buffer], &[], &[], batch.fence);
+            self.queue_end_label(self.transfer_queue);
+            submitted
+        });
+        batch.submission = match submitted {
+            Ok(submission) => submission,
+            Err(e) => {
+                // It never reached the queue, so nothing is using the staging memory.
+                uploads.recycle(self, batch);
+                return Err(e).context("Could not submit uploads");
+            }
+        };
+        uploads.in_flight.push(batch);
+        Ok(UploadTicket(serial))
+    }
+
+    // Frees the staging memory of every batch the GPU has finished with.
+    pub unsafe fn retire_uploads(&self) -> Result<()> {
+        let mut uploads = self.uploads.borrow_mut();
+        let mut i = 0;
+        while i < uploads.in_flight.len() {
+            let (submission, fence) = (uploads.in_flight[i].submission, uploads.in_flight[i].fence);
+            if self.submission_done(submission) {
+                if fence != vk::Fence::null() {
+                    self.device.reset_fences(&[fence]).context("Could not reset upload fence")?;
+                }
+                let batch = uploads.in_flight.swap_remove(i);
+                uploads.recycle(self, batch);
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub unsafe fn upload_done(&self, ticket: UploadTicket) -> Result<bool> {
+        self.retire_uploads()?;
+        Ok(ticket.0 < self.uploads.borrow().oldest_pending())
+    }
+
+    // Blocks until the ticket is done, submitting its batch first if it's still recording.
+    pub unsafe fn wait_upload(&self, ticket: UploadTicket) -> Result<()> {
+        let recording = self.uploads.borrow().recording.as_ref().map_or(false, |b| b.serial <= ticket.0);
+        if recording {
+            self.flush_uploads()?;
+        }
+        let submissions: Vec<u64> = self
+            .uploads
+            .borrow()
+            .in_flight
+            .iter()
+            .filter(|b| b.serial <= ticket.0)
+            .map(|b| b.submission)
+            .collect();
+        for submission in submissions {
+            self.wait_submission(submission, u64::MAX)
+                .context("Could not wait for uploads")?;
+        }
+        self.retire_uploads()
+    }
+}
+
+impl Buffer {
+    // A GpuOnly buffer filled with bytes. Not to be used until the ticket is done.
+    pub unsafe fn device_local(
+        device: &Rc<Device>,
+        usage: vk::BufferUsageFlags,
+        bytes: &[u8],
+        name: &str,
+    ) -> Result<(Buffer, UploadTicket)> {
+        let families = device.upload_queue_families();
+        let info = vk::BufferCreateInfo::default()
+            .size(bytes.len() as vk::DeviceSize)
+            .usage(usage | vk::BufferUsageFlags::TRANSFER_DST);
+        let info = if families.is_empty() {
+            info.sharing_mode(vk::SharingMode::EXCLUSIVE)
+        } else {
+            info.sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(&families)
+        };
+        let buffer = Buffer::with_info(device, &info, MemoryUsage::GpuOnly, name)?;
+        let ticket = device
+            .upload_buffer(buffer.handle(), 0, bytes)
+            .with_context(|| format!("Could not upload {}", name))?;
+        Ok((buffer, ticket))
+    }
+}
+
+impl Image {
+    // A GpuOnly colour image with mip 0, layer 0 filled with bytes and left in final_layout.
+    // Usage and sharing are widened as the upload needs. Not to be used until the ticket is done.
+    pub unsafe fn device_local(
+        device: &Rc<Device>,
+        info: &vk::ImageCreateInfo,
+        bytes: &[u8],
+        final_layout: vk::ImageLayout,
+        name: &str,
+    ) -> Result<(Image, UploadTicket)> {
+        let families = device.upload_queue_families();
+        let mut info = *info;
+        info.usage |= vk::ImageUsageFlags::TRANSFER_DST;
+        info.initial_layout = vk::ImageLayout::UNDEFINED;
+        if !families.is_empty() {
+            info.sharing_mode = vk::SharingMode::CONCURRENT;
+            info.queue_family_index_count = families.len() as u32;
+            info.p_queue_family_indices = families.as_ptr();
+        }
+        let image = Image::new(device, &info, MemoryUsage::GpuOnly, name)?;
+        let ticket = device
+            .upload_image(image.handle(), vk::ImageAspectFlags::COLOR, info.extent, bytes, final_layout)
+            .with_context(|| format!("Could not upload {}", name))?;
+        Ok((image, ticket))
+    }
+}
+
+// Scratch space for data that changes every frame. Reset it once the frame's last submission is
+// done, then record copies out of it into that frame's command buffer, outside any render pass.
+// Buffer copies are followed by one barrier(); images need their own layout transitions around
+// the copy, see barriers.rs. The memory is only allocated once something is streamed, so frames
+// that never stream anything don't pay for it.
+pub struct StagingBuffer {
+    pub device: Rc<Device>,
+    pub buffer: Option<Buffer>,
+    pub size: vk::DeviceSize,
+    pub used: vk::DeviceSize,
+    pub name: String,
+}
+
+impl StagingBuffer {
+    pub fn new(device: &Rc<Device>, size: vk::DeviceSize, name: &str) -> StagingBuffer {
+        StagingBuffer {
+            device: device.clone(),
+            buffer: None,
+            size,
+            used: 0,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    // Writes bytes after whatever is already staged, at a multiple of alignment, and returns the
+    // buffer and offset they're at.
+    unsafe fn push(&mut self, bytes: &[u8], alignment: vk::DeviceSize) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        let size = bytes.len() as vk::DeviceSize;
+        let offset = (self.used + alignment - 1) / alignment * alignment;
+        if offset + size > self.size {
+            bail!(
+                "Streaming {} bytes overflows the {} byte staging buffer, {} already used",
+                size,
+                self.size,
+                self.used
+            );
+        }
+        if self.buffer.is_none() {
+            self.buffer = Some(Buffer::new(
+                &self.device,
+                self.size,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                MemoryUsage::Upload,
+                &self.name,
+            )?);
+        }
+        let buffer = self.buffer.as_ref().unwrap();
+        buffer.write(offset, bytes)?;
+        self.used = offset + size;
+        Ok((buffer.handle(), offset))
+    }
+
+    pub unsafe fn copy_to_buffer(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        dst: vk::Buffer,
+        dst_offset: vk::DeviceSize,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let (src, src_offset) = self.push(bytes, STAGING_ALIGNMENT)?;
+        self.device.device.cmd_copy_buffer(
+            command_buffer,
+            src,
+            dst,
+            &[vk::BufferCopy {
+                src_offset,
+                dst_offset,
+                size: bytes.len() as vk::DeviceSize,
+            }],
+        );
+        Ok(())
+    }
+
+    // Fills mip 0, layer 0 of dst from tightly packed texels. dst has to be in
+    // TRANSFER_DST_OPTIMAL by the time the copy runs.
+    pub unsafe fn copy_to_image(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        dst: vk::Image,
+        format: vk::Format,
+        aspect: vk::ImageAspectFlags,
+        extent: vk::Extent3D,
+        bytes: &[u8],
+    ) -> Result<()> {
+        // The offset has to be a multiple of both 4 and the texel block size.
+        let block = copy_block_size(format, aspect).with_context(|| format!("Can't stream into {:?} images", format))?;
+        let alignment = match block % 4 {
+            0 => block,
+            2 => block * 2,
+            _ => block * 4,
+        };
+        let (src, src_offset) = self.push(bytes, alignment)?;
+        self.device.device.cmd_copy_buffer_to_image(
+            command_buffer,
+            src,
+            dst,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[vk::BufferImageCopy {
+                buffer_offset: src_offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: aspect,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: extent,
+            }],
+        );
+        Ok(())
+    }
+
+    // Makes this frame's copies visible to the stages that read them. Does nothing if there
+    // weren't any.
+    pub unsafe fn barrier(&self, command_buffer: vk::CommandBuffer, dst_stage: vk::PipelineStageFlags, dst_access: vk::AccessFlags) {
+        if self.used == 0 {
+            return;
+        }
+        self.device.device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(dst_access)],
+            &[],
+            &[],
+        );
+    }
+}
+
+// Bytes per texel block of format in buffer memory, when copying aspect. Covers the core 1.0
+// formats; anything else is None.
+fn copy_block_size(format: vk::Format, aspect: vk::ImageAspectFlags) -> Option<vk::DeviceSize> {
+    use vk::Format as F;
+    if aspect == vk::ImageAspectFlags::STENCIL {
+        return Some(1);
+    }
+    let depth = match format {
+        F::D16_UNORM_S8_UINT => Some(2),
+        F::D24_UNORM_S8_UINT | F::D32_SFLOAT_S8_UINT => Some(4),
+        _ => None,
+    };
+    if depth.is_some() {
+        return depth;
+    }
+    // Ranges of consecutive format values, first and last inclusive.
+    const SIZES: &[(vk::Format, vk::Format, vk::DeviceSize)] = &[
+        (F::R4G4_UNORM_PACK8, F::R4G4_UNORM_PACK8, 1),
+        (F::R4G4B4A4_UNORM_PACK16, F::A1R5G5B5_UNORM_PACK16, 2),
+        (F::R8_UNORM, F::R8_SRGB, 1),
+        (F::R8G8_UNORM, F::R8G8_SRGB, 2),
+        (F::R8G8B8_UNORM, F::B8G8R8_SRGB, 3),
+        (F::R8G8B8A8_UNORM, F::A2B10G10R10_SINT_PACK32, 4),
+        (F::R16_UNORM, F::R16_SFLOAT, 2),
+        (F::R16G16_UNORM, F::R16G16_SFLOAT, 4),
+        (F::R16G16B16_UNORM, F::R16G16B16_SFLOAT, 6),
+        (F::R16G16B16A16_UNORM, F::R16G16B16A16_SFLOAT, 8),
+        (F::R32_UINT, F::R32_SFLOAT, 4),
+        (F::R32G32_UINT, F::R32G32_SFLOAT, 8),
+        (F::R32G32B32_UINT, F::R32G32B32_SFLOAT, 12),
+        (F::R32G32B32A32_UINT, F::R32G32B32A32_SFLOAT, 16),
+        (F::R64_UINT, F::R64_SFLOAT, 8),
+        (F::R64G64_UINT, F::R64G64_SFLOAT, 16),
+        (F::R64G64B64_UINT, F::R64G64B64_SFLOAT, 24),
+        (F::R64G64B64A64_UINT, F::R64G64B64A64_SFLOAT, 32),
+        (F::B10G11R11_UFLOAT_PACK32, F::E5B9G9R9_UFLOAT_PACK32, 4),
+        (F::D16_UNORM, F::D16_UNORM, 2),
+        (F::X8_D24_UNORM_PACK32, F::D32_SFLOAT, 4),
+        (F::S8_UINT, F::S8_UINT, 1),
+        (F::BC1_RGB_UNORM_BLOCK, F::BC1_RGBA_SRGB_BLOCK, 8),
+        (F::BC2_UNORM_BLOCK, F::BC3_SRGB_BLOCK, 16),
+        (F::BC4_UNORM_BLOCK, F::BC4_SNORM_BLOCK, 8),
+        (F::BC5_UNORM_BLOCK, F::BC7_SRGB_BLOCK, 16),
+        (F::ETC2_R8G8B8_UNORM_BLOCK, F::ETC2_R8G8B8A1_SRGB_BLOCK, 8),
+        (F::ETC2_R8G8B8A8_UNORM_BLOCK, F::ETC2_R8G8B8A8_SRGB_BLOCK, 16),
+        (F::EAC_R11_UNORM_BLOCK, F::EAC_R11_SNORM_BLOCK, 8),
+        (F::EAC_R11G11_UNORM_BLOCK, F::ASTC_12X12_SRGB_BLOCK, 16),
+    ];
+    SIZES
+        .iter()
+        .find(|&&(first, last, _)| (first.as_raw()..=last.as_raw()).contains(&format.as_raw()))
+        .map(|&(_, _, size)| size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_block_sizes() {
+        let color = vk::ImageAspectFlags::COLOR;
+        assert_eq!(copy_block_size(vk::Format::R8G8B8_SRGB, color), Some(3));
+        assert_eq!(copy_block_size(vk::Format::B8G8R8A8_UNORM, color), Some(4));
+        assert_eq!(copy_block_size(vk::Format::R16G16B16_SFLOAT, color), Some(6));
+        assert_eq!(copy_block_size(vk::Format::R32G32B32_SFLOAT, color), Some(12));
+        assert_eq!(copy_block_size(vk::Format::R64G64B64A64_SFLOAT, color), Some(32));
+        assert_eq!(copy_block_size(vk::Format::BC1_RGB_UNORM_BLOCK, color), Some(8));
+        assert_eq!(copy_block_size(vk::Format::ASTC_12X12_SRGB_BLOCK, color), Some(16));
+        assert_eq!(copy_block_size(vk::Format::D24_UNORM_S8_UINT, vk::ImageAspectFlags::DEPTH), Some(4));
+        assert_eq!(copy_block_size(vk::Format::D24_UNORM_S8_UINT, vk::ImageAspectFlags::STENCIL), Some(1));
+        assert_eq!(copy_block_size(vk::Format::UNDEFINED, color), None);
+        assert_eq!(copy_block_size(vk::Format::G8_B8R8_2PLANE_420_UNORM, color), None);
+    }
+}
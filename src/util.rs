@@ -16,4 +16,50 @@ pub fn as_byte_slice<T>(thing: &T) -> &[u8] {
     unsafe {
         std::slice::from_raw_parts(thing as *const T as *const u8, std::mem::size_of::<T>())
     }
-}
\ No newline at end of file
+}
+
+pub fn slice_as_bytes<T>(things: &[T]) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(things.as_ptr() as *const u8, std::mem::size_of_val(things))
+    }
+}
+
+pub unsafe fn raw_slice<'a, T>(ptr: *const T, count: u32) -> &'a [T] {
+    if ptr.is_null() || count == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, count as usize)
+    }
+}
+
+// Drops the patch number, since only major.minor matters when deciding what core API to use.
+pub fn api_version_min(a: u32, b: u32) -> u32 {
+    let v = std::cmp::min(a, b);
+    ash::vk::make_api_version(0, ash::vk::api_version_major(v), ash::vk::api_version_minor(v), 0)
+}
+
+pub fn version_string(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        ash::vk::api_version_major(version),
+        ash::vk::api_version_minor(version),
+        ash::vk::api_version_patch(version)
+    )
+}
+
+pub fn cstr_string(chars: &[std::os::raw::c_char]) -> String {
+    safer_cstr(chars).map_or(String::new(), |s| s.to_string_lossy().into_owned())
+}
+
+// The usual 8-4-4-4-12 form.
+pub fn uuid_string(uuid: &[u8; 16]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
@@ -1,14 +1,30 @@
 use crate::device::*;
+use crate::renderer::*;
 use crate::swapsurface::*;
 use crate::perframe::*;
+use crate::registry::*;
 
+use anyhow::{Context, Result};
+use ash::vk;
 use winit::window::Window;
 use std::rc::Rc;
 
 
+pub const FRAMES_IN_FLIGHT: usize = 4;
+
+pub fn window_extent(window: &Window) -> vk::Extent2D {
+    let size = window.inner_size();
+    vk::Extent2D {
+        width: size.width,
+        height: size.height,
+    }
+}
 
 pub struct VulkanWindow {
-    pub window: Window,
+    pub label: String,
+    // None for headless windows, which render to a surface that never reaches a display.
+    pub window: Option<Window>,
+    pub headless_extent: vk::Extent2D,
     pub surface: Rc<Surface>,
     pub device: Rc<Device>,
     pub swap: PerSwapchain,
@@ -21,11 +37,125 @@ pub struct VulkanWindow {
     pub anim_start_time: std::time::Instant,
     pub shape_rotate_speed: f32,
     pub color_rotate_speed: f32,
-    pub background_color: [f32; 4]
+    pub background_color: [f32; 4],
+
+    // Makes Renderer::render fail with this error once frame_count reaches the given frame.
+    pub injected_fault: Option<(usize, vk::Result)>,
+}
+
+fn create_per_frame(device: &Rc<Device>, label: &str) -> Result<Vec<PerFrame>> {
+    let _owner = OwnerScope::enter(label);
+    (0..FRAMES_IN_FLIGHT)
+        .map(|i| PerFrame::new(device.clone(), &format!("{} perframe {}", label, i)))
+        .collect::<Result<Vec<PerFrame>>>()
+        .context("Could not create per-frame objects")
+}
+
+impl VulkanWindow {
+    // The swapchain should already have its framebuffers.
+    pub fn new(
+        label: String,
+        window: Option<Window>,
+        headless_extent: vk::Extent2D,
+        surface: Rc<Surface>,
+        swap: PerSwapchain,
+    ) -> Result<VulkanWindow> {
+        let device = swap.device.clone();
+        let per_frame = create_per_frame(&device, &label)?;
+
+        Ok(VulkanWindow {
+            label,
+            window,
+            headless_extent,
+            surface,
+            device,
+            swap,
+            per_frame,
+
+            frame_count: 0,
+            count_start_time: std::time::Instant::now(),
+            count_start_frame: 0,
+
+            anim_start_time: std::time::Instant::now(),
+            shape_rotate_speed: 0.0,
+            color_rotate_speed: 0.0,
+            background_color: [ 1.0, 1.0, 1.0, 0.0 ],
+
+            injected_fault: None,
+        })
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        match &self.window {
+            Some(window) => window_extent(window),
+            None => self.headless_extent,
+        }
+    }
+
+    pub fn recreate_swapchain(&mut self, renderer: &Renderer) -> Result<()> {
+        self.swap = PerSwapchain::new(
+            self.device.clone(),
+            self.extent(),
+            self.surface.clone(),
+            Some(renderer),
+            Some(&self.swap),
+            &self.label,
+        )
+        .context("Recreating swapchain")?;
+        Ok(())
+    }
+
+    // After ERROR_SURFACE_LOST_KHR. Only this window's surface and swapchain are replaced.
+    pub fn recreate_surface(&mut self, renderer: &Renderer) -> Result<()> {
+        let loaders = self.device.loaders.clone();
+
+        // The native window only takes a new swapchain once the old one is gone, so it can't
+        // wait in the deletion queue. Swapping in the placeholder lets go of the lost surface,
+        // which goes once any swapchains retired before it are out of the deletion queue too.
+        self.swap.release(&self.frame_submissions());
+        let placeholder = Rc::new(Surface::none(loaders.clone()));
+        self.swap.surface = placeholder.clone();
+        self.surface = placeholder;
+
+        let surface = unsafe {
+            match &self.window {
+                Some(window) => Surface::for_window(loaders, window)?,
+                None => Surface::headless(loaders)?,
+            }
+        };
+        self.surface = Rc::new(surface);
+        self.swap = PerSwapchain::new(
+            self.device.clone(),
+            self.extent(),
+            self.surface.clone(),
+            Some(renderer),
+            None,
+            &self.label,
+        )
+        .context("Creating swapchain for the new surface")?;
+        Ok(())
+    }
+
+    // After ERROR_DEVICE_LOST, moves the window onto a new device. The OS window, surface and
+    // animation state are kept. Without a renderer the caller has to create the framebuffers.
+    pub fn rebuild(&mut self, device: Rc<Device>, renderer: Option<&Renderer>) -> Result<()> {
+        self.swap.release(&self.frame_submissions());
+        self.per_frame.clear();
+        self.swap = PerSwapchain::new(device.clone(), self.extent(), self.surface.clone(), renderer, None, &self.label)
+            .context("Creating swapchain on the new device")?;
+        self.per_frame = create_per_frame(&device, &self.label)?;
+        self.device = device;
+        Ok(())
+    }
+
+    // The latest submission of each frame in flight, which between them cover all of this
+    // window's work still on the GPU.
+    fn frame_submissions(&self) -> Vec<u64> {
+        self.per_frame.iter().map(|pf| pf.submission).collect()
+    }
 }
 
 impl Drop for VulkanWindow {
     fn drop(&mut self) {
     }
 }
-
@@ -0,0 +1,26 @@
+#[path = "../src/exitcode.rs"]
+mod exitcode;
+
+use exitcode::SKIPPED_EXIT_CODE;
+use std::process::Command;
+
+
+// Runs the binary's --selftest. It needs the Khronos validation layer and a driver with
+// VK_EXT_headless_surface, such as lavapipe, and machines without them skip it rather than fail.
+
+#[test]
+fn selftest() {
+    let output = Command::new(env!("CARGO_BIN_EXE_vulkan-tutorial"))
+        .arg("--selftest")
+        .output()
+        .expect("Could not run the self test");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    if output.status.code() == Some(SKIPPED_EXIT_CODE) {
+        eprintln!("Skipping the self test: {}", stderr.trim());
+        return;
+    }
+    assert!(output.status.success(), "Self test failed with {}:\n{}\n{}", output.status, stdout, stderr);
+    assert!(stdout.contains("Self test passed"), "Self test didn't report passing:\n{}\n{}", stdout, stderr);
+}